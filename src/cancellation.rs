@@ -0,0 +1,52 @@
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Tells a running request to stop, either because the client sent
+/// `$/cancelRequest` for it or because it ran past the request timeout.
+///
+/// Long lookups call [`CancellationToken::check`] between units of work and
+/// return its error, which the server turns into the matching error response.
+pub struct CancellationToken {
+    cancelled: AtomicBool,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    /// A token that expires `timeout` from now, or never when it is `None`.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+        }
+    }
+
+    /// A token that is never cancelled, for work outside of a request.
+    pub fn none() -> Self {
+        Self::new(None)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Fails with [`io::ErrorKind::Interrupted`] once the request is cancelled
+    /// and with [`io::ErrorKind::TimedOut`] once it is past its deadline.
+    pub fn check(&self) -> io::Result<()> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "Request cancelled",
+            ));
+        }
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Request timed out"));
+        }
+        Ok(())
+    }
+}
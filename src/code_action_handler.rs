@@ -0,0 +1,99 @@
+use std::io;
+
+use lsp_server::{Message, Request, Response};
+use lsp_types::{
+    CodeAction, CodeActionKind, CodeActionOrCommand, CodeActionParams, Command, NumberOrString,
+};
+
+use crate::{
+    logger::Logger,
+    lsp_error::LspError,
+    tag_diagnostics::{TagDiagnostics, TagProblem, DIAGNOSTIC_SOURCE},
+    tag_generator::{GENERATE_TAGS_COMMAND, REFRESH_TAGS_COMMAND},
+    LspServer,
+};
+
+pub struct CodeActionHandler;
+
+impl CodeActionHandler {
+    /// The command that fixes `problem`: generating tags for the workspace, or
+    /// regenerating its outdated tags file. A missing ctags command has no
+    /// fix the server can run, since every fix needs it.
+    fn command(problem: &TagProblem) -> Option<Command> {
+        match problem {
+            TagProblem::MissingTags { folder } => Some(Command {
+                title: format!("Generate tags for {}", TagProblem::display(folder)),
+                command: GENERATE_TAGS_COMMAND.to_string(),
+                arguments: Some(vec![serde_json::json!(folder)]),
+            }),
+            TagProblem::StaleTags {
+                tag_file,
+                generated: true,
+            } => Some(Command {
+                title: format!("Refresh tags file {}", tag_file.display()),
+                command: REFRESH_TAGS_COMMAND.to_string(),
+                arguments: Some(vec![serde_json::json!(tag_file)]),
+            }),
+            TagProblem::StaleTags {
+                generated: false, ..
+            }
+            | TagProblem::MissingCtags { .. } => None,
+        }
+    }
+
+    /// Offers a quick fix for every tags diagnostic of the request that still
+    /// applies to the document. Diagnostics fixed by the same command share
+    /// one action.
+    pub fn handle(&self, req: Request, server: &LspServer) -> Result<(), LspError> {
+        Logger::info(&format!("Received request: {:?}", req.method));
+        let params: CodeActionParams = serde_json::from_value(req.params)?;
+
+        let problems = match params.text_document.uri.to_file_path() {
+            Ok(path) => TagDiagnostics::problems(&server.workspace_manager.read().unwrap(), &path),
+            Err(()) => Vec::new(),
+        };
+        let mut actions: Vec<CodeAction> = Vec::new();
+        for diagnostic in &params.context.diagnostics {
+            if diagnostic.source.as_deref() != Some(DIAGNOSTIC_SOURCE) {
+                continue;
+            }
+            let Some(problem) = problems.iter().find(|problem| {
+                diagnostic.code == Some(NumberOrString::String(problem.code().to_string()))
+            }) else {
+                continue;
+            };
+            let Some(command) = CodeActionHandler::command(problem) else {
+                continue;
+            };
+            match actions
+                .iter_mut()
+                .find(|action| action.command.as_ref() == Some(&command))
+            {
+                Some(action) => action
+                    .diagnostics
+                    .get_or_insert_with(Vec::new)
+                    .push(diagnostic.clone()),
+                None => actions.push(CodeAction {
+                    title: command.title.clone(),
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    diagnostics: Some(vec![diagnostic.clone()]),
+                    command: Some(command),
+                    is_preferred: Some(true),
+                    ..CodeAction::default()
+                }),
+            }
+        }
+        let actions: Vec<CodeActionOrCommand> = actions
+            .into_iter()
+            .map(CodeActionOrCommand::CodeAction)
+            .collect();
+
+        let resp = Response::new_ok(req.id.clone(), actions);
+        server
+            .connection
+            .sender
+            .send(Message::Response(resp))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+}
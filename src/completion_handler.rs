@@ -0,0 +1,147 @@
+use std::{collections::HashSet, io};
+
+use lsp_server::{Message, Request, Response};
+use lsp_types::{
+    CompletionItem, CompletionList, CompletionParams, CompletionResponse, Documentation,
+    MarkupContent, MarkupKind,
+};
+
+use crate::{
+    cancellation::CancellationToken,
+    ctags::{CtagsEntry, CtagsHandler},
+    goto_handler::read_lines,
+    logger::Logger,
+    lsp_error::LspError,
+    tag_locator::TagLocator,
+    LspServer,
+};
+
+pub struct CompletionHandler;
+
+impl CompletionHandler {
+    fn to_item(entry: CtagsEntry) -> CompletionItem {
+        CompletionItem {
+            label: entry.name.clone(),
+            kind: Some(entry.completion_kind()),
+            detail: entry.detail(),
+            data: serde_json::to_value(&entry).ok(),
+            ..CompletionItem::default()
+        }
+    }
+
+    fn complete(
+        &self,
+        server: &LspServer,
+        params: CompletionParams,
+        cancel: &CancellationToken,
+    ) -> Result<CompletionList, LspError> {
+        let position = params.text_document_position.position;
+        let uri = params.text_document_position.text_document.uri;
+
+        let document = server.document(&uri)?;
+        let prefix = document
+            .get_prefix_at_position(position)
+            .unwrap_or_default();
+        let qualifiers = document
+            .get_symbol_context(position)
+            .map(|context| context.qualifiers)
+            .unwrap_or_default();
+
+        let snapshot = server.workspace_manager.read().unwrap().snapshot();
+        let workspaces = &snapshot.workspaces;
+        let limit = snapshot.config.completion_limit;
+        let entries = if !qualifiers.is_empty() {
+            // `Foo::ba` completes the members of `Foo`
+            let mut entries =
+                CtagsHandler::query_scope_ctags(workspaces, &qualifiers.join("::"), &uri, cancel)?;
+            entries.retain(|entry| entry.name.starts_with(&prefix));
+            entries.truncate(limit);
+            entries
+        } else if !prefix.is_empty() {
+            CtagsHandler::query_ctags_prefix(workspaces, &prefix, &uri, limit, cancel)?
+        } else {
+            Vec::new()
+        };
+
+        // A declaration and its definition look the same in the completion menu
+        let mut seen = HashSet::new();
+        let items: Vec<CompletionItem> = entries
+            .into_iter()
+            .filter(|entry| seen.insert((entry.name.clone(), entry.detail())))
+            .map(CompletionHandler::to_item)
+            .collect();
+        Logger::info(&format!(
+            "Found {} completion items for prefix: {}",
+            items.len(),
+            prefix
+        ));
+
+        server.ensure_current(&uri, &document)?;
+
+        // The list is always incomplete so the client queries again as the
+        // prefix grows.
+        Ok(CompletionList {
+            is_incomplete: true,
+            items,
+        })
+    }
+
+    /// Adds the definition line of the tag behind `item` as its documentation.
+    fn resolve(&self, server: &LspServer, mut item: CompletionItem) -> io::Result<CompletionItem> {
+        let Some(entry) = item
+            .data
+            .as_ref()
+            .and_then(|data| serde_json::from_value::<CtagsEntry>(data.clone()).ok())
+        else {
+            return Ok(item);
+        };
+
+        // The file may be open with unsaved edits that moved the tag
+        let Ok(lines) = read_lines(&entry.file, &server.documents) else {
+            return Ok(item);
+        };
+        let Some(line_number) = TagLocator::locate(&entry, &lines).map(|found| found.line) else {
+            return Ok(item);
+        };
+
+        item.documentation = Some(Documentation::MarkupContent(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value: format!(
+                "```\n{}\n```\n\n{}:{}",
+                lines[line_number].trim(),
+                entry.file,
+                line_number + 1
+            ),
+        }));
+        Ok(item)
+    }
+
+    pub fn handle(&self, req: Request, server: &LspServer) -> Result<(), LspError> {
+        Logger::info(&format!("Received request: {:?}", req.method));
+        let params: CompletionParams = serde_json::from_value(req.params)?;
+
+        let cancel = server.cancellation_token(&req.id);
+        let list = self.complete(server, params, &cancel)?;
+        let resp = Response::new_ok(req.id.clone(), CompletionResponse::List(list));
+        server
+            .connection
+            .sender
+            .send(Message::Response(resp))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+
+    pub fn handle_resolve(&self, req: Request, server: &LspServer) -> Result<(), LspError> {
+        Logger::info(&format!("Received request: {:?}", req.method));
+        let item: CompletionItem = serde_json::from_value(req.params)?;
+
+        let item = self.resolve(server, item)?;
+        let resp = Response::new_ok(req.id.clone(), item);
+        server
+            .connection
+            .sender
+            .send(Message::Response(resp))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+}
@@ -1,76 +1,739 @@
 use std::{
-    io::{self},
-    process::Command,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use crate::{logger::Logger, workspace::Workspace};
+use lsp_types::{CompletionItemKind, SymbolKind, Url};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+use crate::{
+    cancellation::CancellationToken,
+    logger::Logger,
+    tag_index::TagIndex,
+    workspace::{TagFile, Workspace, WorkspaceManager},
+};
+
+/// Number of tag lines scanned between two cancellation checks.
+const CANCEL_CHECK_INTERVAL: usize = 4096;
+
+/// Extension fields that name the enclosing scope of a tag, e.g. `class:Foo`.
+const SCOPE_FIELDS: &[&str] = &[
+    "class",
+    "struct",
+    "namespace",
+    "union",
+    "enum",
+    "interface",
+    "module",
+    "package",
+    "function",
+    "method",
+    "trait",
+    "implementation",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TagScope {
+    pub kind: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TypeRef {
+    pub kind: String,
+    pub name: String,
+}
+
+/// Search pattern from the address of a tag, with its `^`/`$` anchors
+/// split off and its escapes removed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TagPattern {
+    pub text: String,
+    pub anchored_start: bool,
+    pub anchored_end: bool,
+}
+
+impl TagPattern {
+    /// Parses a pattern that starts right after its opening `delimiter`.
+    /// Returns the pattern and what follows the closing delimiter.
+    fn parse(address: &str, delimiter: char) -> (TagPattern, &str) {
+        let mut text = String::new();
+        let mut rest = "";
+        let mut chars = address.char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, next)) if next == delimiter || next == '\\' => text.push(next),
+                    Some((_, next)) => {
+                        text.push('\\');
+                        text.push(next);
+                    }
+                    None => text.push('\\'),
+                },
+                c if c == delimiter => {
+                    rest = &address[idx + c.len_utf8()..];
+                    break;
+                }
+                c => text.push(c),
+            }
+        }
+
+        let anchored_start = text.starts_with('^');
+        if anchored_start {
+            text.remove(0);
+        }
+        let anchored_end = text.ends_with('$');
+        if anchored_end {
+            text.pop();
+        }
+        (
+            TagPattern {
+                text,
+                anchored_start,
+                anchored_end,
+            },
+            rest,
+        )
+    }
+
+    pub fn matches(&self, line: &str) -> bool {
+        match (self.anchored_start, self.anchored_end) {
+            (true, true) => line == self.text,
+            (true, false) => line.starts_with(&self.text),
+            (false, true) => line.ends_with(&self.text),
+            (false, false) => line.contains(&self.text),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct CtagsEntry {
     pub name: String,
     pub file: String,
-    pub pattern: String,
+    pub pattern: Option<TagPattern>,
     pub kind: String,
+    pub line: Option<u32>,
+    pub scope: Option<TagScope>,
+    pub signature: Option<String>,
+    pub typeref: Option<TypeRef>,
+    pub access: Option<String>,
+    pub inherits: Vec<String>,
+    pub language: Option<String>,
+    pub end: Option<u32>,
+    pub roles: Vec<String>,
+    /// Whether the tag is only visible in its own file (the `file:` field).
+    pub file_scope: bool,
+    /// Extension fields without a typed counterpart above.
+    pub extra: BTreeMap<String, String>,
+}
+
+impl CtagsEntry {
+    /// Returns the full kind name, expanding the single-letter kinds that
+    /// ctags uses for the C family of languages.
+    pub fn kind_name(&self) -> &str {
+        match self.kind.as_str() {
+            "c" => "class",
+            "d" => "macro",
+            "e" => "enumerator",
+            "f" => "function",
+            "g" => "enum",
+            "h" => "header",
+            "l" => "local",
+            "m" => "member",
+            "n" => "namespace",
+            "p" => "prototype",
+            "s" => "struct",
+            "t" => "typedef",
+            "u" => "union",
+            "v" => "variable",
+            "x" => "externvar",
+            "z" => "parameter",
+            "L" => "label",
+            "D" => "macroparam",
+            kind => kind,
+        }
+    }
+
+    /// Short description built from the `typeref:` and `signature:` fields.
+    pub fn detail(&self) -> Option<String> {
+        match (&self.typeref, &self.signature) {
+            (Some(typeref), Some(signature)) => Some(format!("{} {}", typeref.name, signature)),
+            (Some(typeref), None) => Some(typeref.name.clone()),
+            (None, Some(signature)) => Some(signature.clone()),
+            (None, None) => None,
+        }
+    }
+
+    pub fn symbol_kind(&self) -> SymbolKind {
+        match self.kind_name() {
+            "class" => SymbolKind::CLASS,
+            "struct" | "union" => SymbolKind::STRUCT,
+            "interface" | "trait" => SymbolKind::INTERFACE,
+            "namespace" => SymbolKind::NAMESPACE,
+            "module" => SymbolKind::MODULE,
+            "package" => SymbolKind::PACKAGE,
+            "enum" => SymbolKind::ENUM,
+            "enumerator" => SymbolKind::ENUM_MEMBER,
+            "function" | "prototype" if self.scope.is_some() => SymbolKind::METHOD,
+            "function" | "prototype" | "subroutine" => SymbolKind::FUNCTION,
+            "method" => SymbolKind::METHOD,
+            "constructor" => SymbolKind::CONSTRUCTOR,
+            "member" | "field" => SymbolKind::FIELD,
+            "property" => SymbolKind::PROPERTY,
+            "macro" | "constant" => SymbolKind::CONSTANT,
+            "typedef" | "alias" => SymbolKind::TYPE_PARAMETER,
+            "event" => SymbolKind::EVENT,
+            _ => SymbolKind::VARIABLE,
+        }
+    }
+
+    pub fn completion_kind(&self) -> CompletionItemKind {
+        match self.symbol_kind() {
+            SymbolKind::CLASS => CompletionItemKind::CLASS,
+            SymbolKind::STRUCT => CompletionItemKind::STRUCT,
+            SymbolKind::INTERFACE => CompletionItemKind::INTERFACE,
+            SymbolKind::NAMESPACE | SymbolKind::MODULE | SymbolKind::PACKAGE => {
+                CompletionItemKind::MODULE
+            }
+            SymbolKind::ENUM => CompletionItemKind::ENUM,
+            SymbolKind::ENUM_MEMBER => CompletionItemKind::ENUM_MEMBER,
+            SymbolKind::METHOD => CompletionItemKind::METHOD,
+            SymbolKind::FUNCTION => CompletionItemKind::FUNCTION,
+            SymbolKind::CONSTRUCTOR => CompletionItemKind::CONSTRUCTOR,
+            SymbolKind::FIELD => CompletionItemKind::FIELD,
+            SymbolKind::PROPERTY => CompletionItemKind::PROPERTY,
+            SymbolKind::CONSTANT => CompletionItemKind::CONSTANT,
+            SymbolKind::TYPE_PARAMETER => CompletionItemKind::TYPE_PARAMETER,
+            SymbolKind::EVENT => CompletionItemKind::EVENT,
+            _ => CompletionItemKind::VARIABLE,
+        }
+    }
 }
 
 pub struct CtagsHandler;
 
 impl CtagsHandler {
-    pub fn query_ctags(workspaces: &Vec<Workspace>, symbol: &str) -> io::Result<Vec<CtagsEntry>> {
+    /// Looks `symbol` up in every tags file of every workspace. Results from the
+    /// workspace containing `current_uri` come first, then each workspace's
+    /// tags files in priority order. Duplicates are dropped.
+    pub fn query_ctags(
+        workspaces: &[Workspace],
+        symbol: &str,
+        current_uri: &Url,
+        cancel: &CancellationToken,
+    ) -> io::Result<Vec<CtagsEntry>> {
+        CtagsHandler::query(
+            workspaces,
+            current_uri,
+            usize::MAX,
+            cancel,
+            |line| CtagsHandler::tag_name(line) == symbol,
+            |index| index.find(symbol),
+        )
+    }
+
+    /// Returns up to `limit` tags whose name starts with `prefix`, in the same
+    /// order as [`CtagsHandler::query_ctags`]. Names matching the case of
+    /// `prefix` come before the ones that only match ignoring case.
+    pub fn query_ctags_prefix(
+        workspaces: &[Workspace],
+        prefix: &str,
+        current_uri: &Url,
+        limit: usize,
+        cancel: &CancellationToken,
+    ) -> io::Result<Vec<CtagsEntry>> {
+        CtagsHandler::query(
+            workspaces,
+            current_uri,
+            limit,
+            cancel,
+            |line| {
+                CtagsHandler::tag_name(line)
+                    .get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+            },
+            |index| {
+                let mut lines = index.find_prefix(prefix, limit);
+                // Names in another case only count when the names in the same
+                // case leave room for them. Lines found twice are dropped by
+                // `query`.
+                if lines.len() < limit {
+                    lines.extend(index.find_prefix_ignore_case(prefix, limit));
+                }
+                lines
+            },
+        )
+    }
+
+    /// Returns the tags whose scope is `scope`, such as the members of a class,
+    /// in the same order as [`CtagsHandler::query_ctags`].
+    pub fn query_scope_ctags(
+        workspaces: &[Workspace],
+        scope: &str,
+        current_uri: &Url,
+        cancel: &CancellationToken,
+    ) -> io::Result<Vec<CtagsEntry>> {
+        CtagsHandler::query(
+            workspaces,
+            current_uri,
+            usize::MAX,
+            cancel,
+            |line| {
+                CtagsHandler::tag_scope(line)
+                    .is_some_and(|name| CtagsHandler::is_in_scope(&name, scope))
+            },
+            |index| index.find_scope(scope),
+        )
+    }
+
+    fn load_index(tag_file: &TagFile) -> io::Result<Arc<TagIndex>> {
+        TagIndex::load(Path::new(&tag_file.path)).map_err(|e| {
+            Logger::error(&format!(
+                "Failed to read tags file {}: {:?}",
+                tag_file.path, e
+            ));
+            e
+        })
+    }
+
+    fn query(
+        workspaces: &[Workspace],
+        current_uri: &Url,
+        limit: usize,
+        cancel: &CancellationToken,
+        overlay_match: impl Fn(&str) -> bool,
+        lookup: impl Fn(&TagIndex) -> Vec<String>,
+    ) -> io::Result<Vec<CtagsEntry>> {
+        let mut seen = HashSet::new();
+        let mut entries = Vec::new();
+        for workspace in CtagsHandler::ordered_workspaces(workspaces, current_uri) {
+            // Re-tagged files take precedence over the tags files
+            let folder_path = CtagsHandler::folder_path(workspace);
+            entries.extend(
+                workspace
+                    .overlay
+                    .values()
+                    .flatten()
+                    .filter(|line| overlay_match(line))
+                    .filter_map(|line| CtagsHandler::parse_tag(line, &folder_path))
+                    .filter(|entry| seen.insert(entry.clone())),
+            );
+            for tag_file in &workspace.tag_files {
+                if entries.len() >= limit {
+                    return Ok(entries);
+                }
+                cancel.check()?;
+                let index = CtagsHandler::load_index(tag_file)?;
+                let lines = lookup(&index);
+                entries.extend(
+                    lines
+                        .iter()
+                        .filter(|line| {
+                            !CtagsHandler::is_overlaid(line, workspace, index.base_dir())
+                        })
+                        .filter_map(|line| CtagsHandler::parse_tag(line, index.base_dir()))
+                        .filter(|entry| seen.insert(entry.clone())),
+                );
+            }
+        }
+        entries.truncate(limit);
+
+        Ok(entries)
+    }
+
+    fn tag_name(line: &str) -> &str {
+        line.split('\t').next().unwrap_or_default()
+    }
+
+    /// Returns the name of the scope of the tag on `line` without parsing the
+    /// rest of it.
+    pub fn tag_scope(line: &str) -> Option<String> {
+        let (_, _, fields) = CtagsHandler::parse_address(line.splitn(3, '\t').nth(2)?);
+        fields.split('\t').find_map(|field| {
+            let (key, value) = field.split_once(':')?;
+            match key {
+                "scope" => value
+                    .split_once(':')
+                    .map(|(_, name)| CtagsHandler::unescape(name)),
+                _ if SCOPE_FIELDS.contains(&key) => Some(CtagsHandler::unescape(value)),
+                _ => None,
+            }
+        })
+    }
+
+    /// Whether the scope `name` of a tag is `scope`, or nested in a way that
+    /// ends with it, like `ns::Foo` or `pkg.Foo` for `Foo`.
+    pub fn is_in_scope(name: &str, scope: &str) -> bool {
+        name.strip_suffix(scope).is_some_and(|parent| {
+            parent.is_empty() || parent.ends_with("::") || parent.ends_with('.')
+        })
+    }
+
+    /// Whether the file of the tag on `line`, read from a tags file whose
+    /// relative names start at `base_dir`, has been re-tagged, which makes the
+    /// line stale.
+    fn is_overlaid(line: &str, workspace: &Workspace, base_dir: &Path) -> bool {
+        !workspace.overlay.is_empty()
+            && line.split('\t').nth(1).is_some_and(|file| {
+                workspace
+                    .overlay
+                    .contains_key(&CtagsHandler::resolve_file(file, base_dir))
+            })
+    }
+
+    /// Calls `visit` with the name and raw line of every tag in every tags file
+    /// of every workspace, so callers only parse the tags they keep, along with
+    /// the directory to parse the line against. Tags of re-tagged files come
+    /// from the overlay instead. The scan stops with an error once `cancel` is
+    /// cancelled.
+    pub fn scan_ctags(
+        workspaces: &[Workspace],
+        cancel: &CancellationToken,
+        mut visit: impl FnMut(&Path, &str, &str),
+    ) -> io::Result<()> {
         for workspace in workspaces {
-            if let Some(tags_path) = &workspace.tag_file_path {
-                let output = Command::new("readtags")
-                    .arg("-t")
-                    .arg(tags_path)
-                    .arg("-e")
-                    .arg(symbol)
-                    .output()
-                    .map_err(|e| {
-                        Logger::error(&format!("Failed to execute readtags: {:?}", e));
-                        io::Error::new(io::ErrorKind::Other, "Failed to execute readtags")
-                    })?;
-                let stdout = String::from_utf8(output.stdout).map_err(|e| {
-                    Logger::error(&format!("Invalid UTF-8 in readtags output: {:?}", e));
-                    io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Invalid UTF-8 in readtags output",
-                    )
+            cancel.check()?;
+            let folder_path = CtagsHandler::folder_path(workspace);
+            for line in workspace.overlay.values().flatten() {
+                visit(&folder_path, CtagsHandler::tag_name(line), line);
+            }
+            for tag_file in &workspace.tag_files {
+                let mut scanned = 0;
+                let index = CtagsHandler::load_index(tag_file)?;
+                let base_dir = index.base_dir();
+                index.try_for_each(|line| {
+                    scanned += 1;
+                    if scanned % CANCEL_CHECK_INTERVAL == 0 {
+                        cancel.check()?;
+                    }
+                    if !CtagsHandler::is_overlaid(line, workspace, base_dir) {
+                        visit(base_dir, CtagsHandler::tag_name(line), line)
+                    }
+                    Ok::<(), io::Error>(())
                 })?;
-                let entries: Vec<CtagsEntry> = stdout
-                    .lines()
-                    .filter_map(|line| CtagsHandler::parse_tag(line, workspace))
-                    .collect();
+            }
+        }
+        Ok(())
+    }
 
-                return Ok(entries);
+    /// Orders workspaces so that the one containing `uri` comes first.
+    fn ordered_workspaces<'a>(workspaces: &'a [Workspace], uri: &Url) -> Vec<&'a Workspace> {
+        let mut ordered: Vec<&Workspace> = workspaces.iter().collect();
+        let current = uri
+            .to_file_path()
+            .ok()
+            .and_then(|path| WorkspaceManager::innermost(workspaces, &path));
+        if let Some(idx) = current {
+            let workspace = ordered.remove(idx);
+            ordered.insert(0, workspace);
+        }
+        ordered
+    }
+
+    /// Returns every tag defined in `path`.
+    pub fn query_file_ctags(workspaces: &[Workspace], path: &Path) -> io::Result<Vec<CtagsEntry>> {
+        let mut seen = HashSet::new();
+        let mut entries = Vec::new();
+        let file = path.display().to_string();
+        for workspace in workspaces {
+            if let Some(lines) = workspace.overlay.get(&file) {
+                let folder_path = CtagsHandler::folder_path(workspace);
+                entries.extend(
+                    lines
+                        .iter()
+                        .filter_map(|line| CtagsHandler::parse_tag(line, &folder_path))
+                        .filter(|entry| seen.insert(entry.clone())),
+                );
+                continue;
+            }
+
+            for tag_file in &workspace.tag_files {
+                let index = CtagsHandler::load_index(tag_file)?;
+                for column in &index.file_columns(path) {
+                    entries.extend(
+                        index
+                            .find_file(column)
+                            .iter()
+                            .filter_map(|line| CtagsHandler::parse_tag(line, index.base_dir()))
+                            .filter(|entry| seen.insert(entry.clone())),
+                    );
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns every source file referenced by the tags files, which is the set
+    /// of files indexed for the workspaces.
+    pub fn indexed_files(workspaces: &[Workspace]) -> io::Result<Vec<String>> {
+        let mut files = BTreeSet::new();
+        for workspace in workspaces {
+            files.extend(
+                workspace
+                    .overlay
+                    .iter()
+                    .filter(|(_, lines)| !lines.is_empty())
+                    .map(|(file, _)| file.clone()),
+            );
+            for tag_file in &workspace.tag_files {
+                let index = CtagsHandler::load_index(tag_file)?;
+                files.extend(
+                    index
+                        .files()
+                        .into_iter()
+                        .map(|file| CtagsHandler::resolve_file(file, index.base_dir()))
+                        .filter(|file| !workspace.overlay.contains_key(file)),
+                );
             }
         }
+        Ok(files.into_iter().collect())
+    }
 
-        Ok(Vec::new())
+    /// The directory of `workspace`, which its re-tagged files are under.
+    fn folder_path(workspace: &Workspace) -> PathBuf {
+        workspace.folder.uri.to_file_path().unwrap_or_default()
     }
 
-    fn parse_tag(line: &str, workspace: &Workspace) -> Option<CtagsEntry> {
-        let parts: Vec<&str> = line.split('\t').collect();
-        if parts.len() < 4 {
-            return None;
+    /// Resolves the file column of a tag, which is relative to `base_dir`
+    /// unless it is absolute.
+    fn resolve_file(file: &str, base_dir: &Path) -> String {
+        if Path::new(file).is_absolute() {
+            file.to_string()
+        } else {
+            base_dir
+                .join(file.strip_prefix("./").unwrap_or(file))
+                .display()
+                .to_string()
         }
+    }
 
-        let pattern =
-            parts[2].trim_matches(|c| c == '/' || c == '^' || c == '$' || c == ';' || c == '"');
+    /// Parses a tag line read from a tags file whose relative file names
+    /// start at `base_dir`.
+    pub fn parse_tag(line: &str, base_dir: &Path) -> Option<CtagsEntry> {
+        let mut columns = line.splitn(3, '\t');
+        let name = columns.next()?;
+        let file = columns.next()?;
+        let (address_line, pattern, fields) = CtagsHandler::parse_address(columns.next()?);
 
-        let kind_parts: Vec<&str> = parts[3].split(':').collect();
-        if kind_parts.len() < 2 {
-            return None;
+        let mut entry = CtagsEntry {
+            name: name.to_string(),
+            file: CtagsHandler::resolve_file(file, base_dir),
+            pattern,
+            kind: String::new(),
+            line: None,
+            scope: None,
+            signature: None,
+            typeref: None,
+            access: None,
+            inherits: Vec::new(),
+            language: None,
+            end: None,
+            roles: Vec::new(),
+            file_scope: false,
+            extra: BTreeMap::new(),
+        };
+
+        let fields = fields.split('\t').filter(|field| !field.is_empty());
+        for (idx, field) in fields.enumerate() {
+            match field.split_once(':') {
+                Some((key, value)) => {
+                    CtagsHandler::parse_field(&mut entry, key, &CtagsHandler::unescape(value))
+                }
+                // The kind may be written as a bare letter right after the address.
+                None if idx == 0 => entry.kind = field.to_string(),
+                None => {
+                    entry.extra.insert(field.to_string(), String::new());
+                }
+            }
         }
+        // The `line:` field wins over a line number address
+        entry.line = entry.line.or(address_line);
 
-        Some(CtagsEntry {
-            name: parts[0].to_string(),
-            file: format!(
-                "{}/{}",
-                workspace.folder.uri.to_file_path().unwrap().display(),
-                parts[1]
-            ),
-            pattern: pattern.to_string(),
-            kind: kind_parts[1].to_string(),
+        Some(entry)
+    }
+
+    /// Parses the ex command address of a tag: a line number, a `/pattern/` or
+    /// `?pattern?` search, or both separated by `;` as written by
+    /// `--excmd=combine`. Returns the line, the pattern and the remaining
+    /// extension fields.
+    fn parse_address(address: &str) -> (Option<u32>, Option<TagPattern>, &str) {
+        let mut line = None;
+        let mut pattern = None;
+        let mut rest = address;
+        loop {
+            match rest.chars().next() {
+                Some(delimiter @ ('/' | '?')) => {
+                    let (parsed, remaining) = TagPattern::parse(&rest[1..], delimiter);
+                    pattern = Some(parsed);
+                    rest = remaining;
+                }
+                Some(c) if c.is_ascii_digit() => {
+                    let end = rest
+                        .find(|c: char| !c.is_ascii_digit())
+                        .unwrap_or(rest.len());
+                    line = rest[..end].parse().ok();
+                    rest = &rest[end..];
+                }
+                _ => {
+                    // Unknown ex command, skip it entirely
+                    let end = rest.find(";\"\t").or_else(|| rest.find('\t'));
+                    rest = end.map_or("", |end| &rest[end..]);
+                }
+            }
+
+            if let Some(remaining) = rest.strip_prefix(";\"") {
+                rest = remaining;
+                break;
+            }
+            match rest.strip_prefix(';') {
+                Some(remaining) if !remaining.is_empty() => rest = remaining,
+                _ => break,
+            }
+        }
+        (line, pattern, rest)
+    }
+
+    fn parse_field(entry: &mut CtagsEntry, key: &str, value: &str) {
+        let split_pair = |value: &str| {
+            value
+                .split_once(':')
+                .map(|(kind, name)| (kind.to_string(), name.to_string()))
+        };
+        let split_list = |value: &str| {
+            value
+                .split(',')
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+
+        match key {
+            "kind" => entry.kind = value.to_string(),
+            "line" => entry.line = value.parse().ok(),
+            "end" => entry.end = value.parse().ok(),
+            "signature" => entry.signature = Some(value.to_string()),
+            "access" => entry.access = Some(value.to_string()),
+            "language" => entry.language = Some(value.to_string()),
+            "inherits" => entry.inherits = split_list(value),
+            "roles" => entry.roles = split_list(value),
+            "file" => entry.file_scope = true,
+            "typeref" => {
+                entry.typeref = split_pair(value).map(|(kind, name)| TypeRef { kind, name })
+            }
+            "scope" => entry.scope = split_pair(value).map(|(kind, name)| TagScope { kind, name }),
+            _ if SCOPE_FIELDS.contains(&key) => {
+                entry.scope = Some(TagScope {
+                    kind: key.to_string(),
+                    name: value.to_string(),
+                })
+            }
+            _ => {
+                entry.extra.insert(key.to_string(), value.to_string());
+            }
+        }
+    }
+
+    /// Reverts the escaping applied to extension field values.
+    fn unescape(value: &str) -> String {
+        if !value.contains('\\') {
+            return value.to_string();
+        }
+
+        let mut result = String::with_capacity(value.len());
+        let mut chars = value.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                result.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('t') => result.push('\t'),
+                Some('n') => result.push('\n'),
+                Some('r') => result.push('\r'),
+                Some('\\') => result.push('\\'),
+                Some(other) => {
+                    result.push('\\');
+                    result.push(other);
+                }
+                None => result.push('\\'),
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(text: &str, anchored_start: bool, anchored_end: bool) -> Option<TagPattern> {
+        Some(TagPattern {
+            text: text.to_string(),
+            anchored_start,
+            anchored_end,
         })
     }
+
+    #[test]
+    fn parse_pattern() {
+        assert_eq!(
+            TagPattern::parse("^int main(void)$/;\"\tf", '/'),
+            (pattern("int main(void)", true, true).unwrap(), ";\"\tf")
+        );
+        // Escaped delimiters and backslashes are unescaped, other escapes kept
+        assert_eq!(
+            TagPattern::parse(r"^a \/ b \\ c \d/", '/').0,
+            pattern(r"a / b \ c \d", true, false).unwrap()
+        );
+        assert_eq!(
+            TagPattern::parse(r"x \? y$?", '?').0,
+            pattern("x ? y", false, true).unwrap()
+        );
+        // An unterminated pattern takes the rest of the address
+        assert_eq!(
+            TagPattern::parse("^abc", '/'),
+            (pattern("abc", true, false).unwrap(), "")
+        );
+    }
+
+    #[test]
+    fn parse_address() {
+        assert_eq!(
+            CtagsHandler::parse_address("42;\"\tf"),
+            (Some(42), None, "\tf")
+        );
+        assert_eq!(
+            CtagsHandler::parse_address("/^int x;$/;\"\tv\tline:3"),
+            (None, pattern("int x;", true, true), "\tv\tline:3")
+        );
+        assert_eq!(
+            CtagsHandler::parse_address("?^int x;$?;\"\tv"),
+            (None, pattern("int x;", true, true), "\tv")
+        );
+        // --excmd=combine writes the line number before the pattern
+        assert_eq!(
+            CtagsHandler::parse_address("12;/^int x;$/;\"\tv"),
+            (Some(12), pattern("int x;", true, true), "\tv")
+        );
+        // Unknown ex commands are skipped up to the extension fields
+        assert_eq!(
+            CtagsHandler::parse_address("normal! gg;\"\tv"),
+            (None, None, "\tv")
+        );
+        assert_eq!(CtagsHandler::parse_address("42"), (Some(42), None, ""));
+    }
+
+    #[test]
+    fn parse_tag_address() {
+        let entry =
+            CtagsHandler::parse_tag("x\tf.c\t7;/^int x;$/;\"\tv\tline:3", Path::new("/src"))
+                .unwrap();
+        // The line field wins over the line number of the address
+        assert_eq!(entry.line, Some(3));
+        assert_eq!(entry.pattern, pattern("int x;", true, true));
+        assert_eq!(entry.kind, "v");
+        assert_eq!(entry.file, "/src/f.c");
+    }
 }
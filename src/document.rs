@@ -1,62 +1,267 @@
-use std::{collections::HashMap, io};
+use std::{collections::HashMap, io, sync::Arc};
 
-use lsp_types::{Position, TextDocumentContentChangeEvent, Url};
+use lsp_types::{Position, Range, TextDocumentContentChangeEvent, Url};
 
+use crate::position_encoding::PositionEncoding;
+
+/// Characters, besides alphanumerics and `_`, that belong to identifiers in
+/// some languages. Keyed by LSP language id.
+const DEFAULT_WORD_CHARACTERS: &[(&str, &str)] = &[
+    ("lisp", "-*+!?<>=/"),
+    ("commonlisp", "-*+!?<>=/"),
+    ("emacs-lisp", "-*+!?<>=/"),
+    ("scheme", "-*+!?<>=/"),
+    ("racket", "-*+!?<>=/"),
+    ("clojure", "-*+!?<>=/"),
+    ("php", "$"),
+    ("perl", "$"),
+    ("ruby", "?!"),
+];
+
+/// Decides which characters make up an identifier.
+#[derive(Debug, Clone, Default)]
+pub struct WordRules {
+    extra_chars: Vec<char>,
+}
+
+impl WordRules {
+    /// Rules for `language_id`; `overrides` maps language ids to the extra
+    /// word characters configured by the user.
+    pub fn for_language(language_id: &str, overrides: &HashMap<String, String>) -> Self {
+        let extra_chars = overrides
+            .get(language_id)
+            .map(String::as_str)
+            .or_else(|| {
+                DEFAULT_WORD_CHARACTERS
+                    .iter()
+                    .find(|(id, _)| *id == language_id)
+                    .map(|(_, chars)| *chars)
+            })
+            .unwrap_or_default();
+        Self {
+            extra_chars: extra_chars.chars().collect(),
+        }
+    }
+
+    pub fn is_word_char(&self, c: char) -> bool {
+        c.is_alphanumeric() || c == '_' || self.extra_chars.contains(&c)
+    }
+
+    /// Returns the byte offset where the word ending at `end` starts.
+    fn word_start(&self, text: &str, end: usize) -> usize {
+        text[..end]
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| self.is_word_char(*c))
+            .last()
+            .map_or(end, |(idx, _)| idx)
+    }
+
+    /// Returns the byte offset where the word starting at `start` ends.
+    fn word_end(&self, text: &str, start: usize) -> usize {
+        text[start..]
+            .char_indices()
+            .find(|(_, c)| !self.is_word_char(*c))
+            .map_or(text.len(), |(idx, _)| start + idx)
+    }
+}
+
+/// The identifier under the cursor together with what surrounds it.
+#[derive(Debug, Clone)]
+pub struct SymbolContext {
+    pub name: String,
+    /// Qualifiers written before the identifier, outermost first, e.g.
+    /// `["ns", "Type"]` for `ns::Type::method`.
+    pub qualifiers: Vec<String>,
+    /// Expression the identifier is accessed on, e.g. `obj` for `obj.field`
+    /// or `this` for `this->member`.
+    pub receiver: Option<String>,
+    pub range: Range,
+}
+
+#[derive(Clone)]
 pub struct TextDocument {
     text: String,
+    /// Byte offset where every line of `text` starts.
+    line_starts: Vec<usize>,
+    /// Version of the last content the client synchronized.
+    version: i32,
+    word_rules: WordRules,
+    encoding: PositionEncoding,
 }
 
 impl TextDocument {
-    pub fn new(text: String) -> Self {
-        Self { text }
+    pub fn new(
+        text: String,
+        version: i32,
+        word_rules: WordRules,
+        encoding: PositionEncoding,
+    ) -> Self {
+        Self {
+            line_starts: TextDocument::line_starts(&text),
+            text,
+            version,
+            word_rules,
+            encoding,
+        }
+    }
+
+    fn line_starts(text: &str) -> Vec<usize> {
+        std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect()
+    }
+
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    pub fn word_rules(&self) -> &WordRules {
+        &self.word_rules
+    }
+
+    /// Returns line `line_number` without its `\n` or `\r\n` terminator.
+    fn line(&self, line_number: usize) -> Option<&str> {
+        let start = *self.line_starts.get(line_number)?;
+        let end = self
+            .line_starts
+            .get(line_number + 1)
+            .map_or(self.text.len(), |next| next - 1);
+        let line = &self.text[start..end];
+        Some(line.strip_suffix('\r').unwrap_or(line))
+    }
+
+    /// Returns every line without its terminator.
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        (0..self.line_starts.len()).filter_map(|line_number| self.line(line_number))
     }
 
     pub fn get_line(&self, line_number: usize) -> io::Result<String> {
-        self.text
-            .lines()
-            .nth(line_number)
+        self.line(line_number)
             .map(|line| line.to_string())
             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Line number out of range"))
     }
 
-    pub fn apply_changes(&mut self, changes: Vec<TextDocumentContentChangeEvent>) {
+    /// Applies the changes of a `didChange` notification, which bring the
+    /// document to `version`. Ranged changes are applied in order, each
+    /// against the text the previous one left.
+    pub fn apply_changes(&mut self, changes: Vec<TextDocumentContentChangeEvent>, version: i32) {
         for change in changes {
             if let Some(range) = change.range {
                 let start = self.position_to_offset(range.start);
-                let end = self.position_to_offset(range.end);
+                let end = self.position_to_offset(range.end).max(start);
                 self.text.replace_range(start..end, &change.text);
             } else {
                 self.text = change.text;
             }
+            self.line_starts = TextDocument::line_starts(&self.text);
         }
+        self.version = version;
     }
 
+    /// Converts `position` to a byte offset in the text. Positions past the end
+    /// of their line are clamped to it, excluding the line terminator, and
+    /// positions past the last line to the end of the text.
     fn position_to_offset(&self, position: Position) -> usize {
-        let mut offset = 0;
-        for (i, line) in self.text.lines().enumerate() {
-            if i == position.line as usize {
-                offset += position.character as usize;
-                break;
+        let line_number = position.line as usize;
+        match self.line(line_number) {
+            Some(line) => {
+                self.line_starts[line_number] + self.encoding.offset(line, position.character)
             }
-            offset += line.len() + 1; // +1 for the newline character
+            None => self.text.len(),
         }
-        offset
+    }
+
+    /// Returns the part of the identifier under the cursor that precedes it.
+    pub fn get_prefix_at_position(&self, position: Position) -> io::Result<String> {
+        let line = self.get_line(position.line as usize)?;
+        let end = self.encoding.offset(&line, position.character);
+        let start = self.word_rules.word_start(&line, end);
+        Ok(line[start..end].to_string())
     }
 
     pub fn get_symbol_at_position(&self, position: Position) -> io::Result<String> {
+        self.get_symbol_context(position)
+            .map(|context| context.name)
+    }
+
+    /// Returns the identifier under the cursor with its `::` qualifier chain
+    /// and the receiver of a `.` or `->` member access.
+    pub fn get_symbol_context(&self, position: Position) -> io::Result<SymbolContext> {
         let line = self.get_line(position.line as usize)?;
-        let symbol_start = line[..position.character as usize]
-            .rfind(|c: char| !c.is_alphanumeric() && c != '_')
-            .map_or(0, |pos| pos + 1);
-        let symbol_end = line[position.character as usize..]
-            .find(|c: char| !c.is_alphanumeric() && c != '_')
-            .map_or(line.len(), |pos| position.character as usize + pos);
-        Ok(line[symbol_start..symbol_end].to_string())
+        let cursor = self.encoding.offset(&line, position.character);
+        let start = self.word_rules.word_start(&line, cursor);
+        let end = self.word_rules.word_end(&line, cursor);
+
+        let mut qualifiers = Vec::new();
+        let mut rest = &line[..start];
+        while let Some(before) = rest.strip_suffix("::") {
+            let qualifier_start = self.word_rules.word_start(before, before.len());
+            if qualifier_start == before.len() {
+                break;
+            }
+            qualifiers.insert(0, before[qualifier_start..].to_string());
+            rest = &before[..qualifier_start];
+        }
+
+        let receiver = [".", "->"]
+            .iter()
+            .find_map(|accessor| rest.strip_suffix(accessor))
+            .map(|before| before[self.receiver_start(before)..].to_string())
+            .filter(|receiver| !receiver.is_empty());
+
+        Ok(SymbolContext {
+            name: line[start..end].to_string(),
+            qualifiers,
+            receiver,
+            range: self.encoding.range(position.line, &line, start, end),
+        })
+    }
+
+    /// Returns the byte offset where the receiver expression ending at the end
+    /// of `text` starts. The expression is a chain of identifiers joined by
+    /// `.`, `->` or `::`, optionally followed by calls or subscripts.
+    fn receiver_start(&self, text: &str) -> usize {
+        let mut end = text.len();
+        loop {
+            // Skip balanced `(...)` and `[...]` groups
+            while let Some(close) = text[..end]
+                .chars()
+                .next_back()
+                .filter(|c| *c == ')' || *c == ']')
+            {
+                let open = if close == ')' { '(' } else { '[' };
+                let mut depth = 0;
+                let Some(group_start) = text[..end].char_indices().rev().find_map(|(idx, c)| {
+                    if c == close {
+                        depth += 1;
+                    } else if c == open {
+                        depth -= 1;
+                    }
+                    (depth == 0).then_some(idx)
+                }) else {
+                    return end;
+                };
+                end = group_start;
+            }
+
+            let start = self.word_rules.word_start(text, end);
+            if start == end {
+                return end;
+            }
+            match ["::", "->", "."]
+                .iter()
+                .find(|connector| text[..start].ends_with(*connector))
+            {
+                Some(connector) => end = start - connector.len(),
+                None => return start,
+            }
+        }
     }
 }
 
 pub struct DocumentsCache {
-    documents: HashMap<Url, TextDocument>,
+    documents: HashMap<Url, Arc<TextDocument>>,
 }
 
 impl DocumentsCache {
@@ -67,18 +272,129 @@ impl DocumentsCache {
     }
 
     pub fn insert(&mut self, uri: Url, document: TextDocument) {
-        self.documents.insert(uri, document);
+        self.documents.insert(uri, Arc::new(document));
     }
 
     pub fn remove(&mut self, uri: &Url) {
         self.documents.remove(uri);
     }
 
-    pub fn get(&self, uri: &Url) -> Option<&TextDocument> {
-        self.documents.get(uri)
+    /// Returns a snapshot of the document, which stays valid while the
+    /// document keeps changing.
+    pub fn get(&self, uri: &Url) -> Option<Arc<TextDocument>> {
+        self.documents.get(uri).cloned()
+    }
+
+    pub fn uris(&self) -> impl Iterator<Item = &Url> {
+        self.documents.keys()
     }
 
+    /// Returns the document for editing. Snapshots still in use keep the old
+    /// contents.
     pub fn get_mut(&mut self, uri: &Url) -> Option<&mut TextDocument> {
-        self.documents.get_mut(uri)
+        self.documents.get_mut(uri).map(Arc::make_mut)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(text: &str, encoding: PositionEncoding) -> TextDocument {
+        TextDocument::new(text.to_string(), 1, WordRules::default(), encoding)
+    }
+
+    fn change(
+        range: Option<((u32, u32), (u32, u32))>,
+        text: &str,
+    ) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: range.map(|(start, end)| {
+                Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1))
+            }),
+            range_length: None,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn position_to_offset_clamps_to_line() {
+        let doc = document("ab\ncd\n", PositionEncoding::Utf16);
+        assert_eq!(doc.position_to_offset(Position::new(0, 0)), 0);
+        assert_eq!(doc.position_to_offset(Position::new(1, 1)), 4);
+        assert_eq!(doc.position_to_offset(Position::new(0, 10)), 2);
+        assert_eq!(doc.position_to_offset(Position::new(2, 0)), 6);
+        assert_eq!(doc.position_to_offset(Position::new(7, 3)), 6);
+    }
+
+    #[test]
+    fn position_to_offset_excludes_crlf() {
+        let doc = document("ab\r\ncd\r\n", PositionEncoding::Utf16);
+        assert_eq!(doc.position_to_offset(Position::new(0, 2)), 2);
+        assert_eq!(doc.position_to_offset(Position::new(0, 5)), 2);
+        assert_eq!(doc.position_to_offset(Position::new(1, 0)), 4);
+        assert_eq!(doc.position_to_offset(Position::new(1, 9)), 6);
+    }
+
+    #[test]
+    fn position_to_offset_counts_in_encoding() {
+        let text = "a😀b\n";
+        assert_eq!(
+            document(text, PositionEncoding::Utf16).position_to_offset(Position::new(0, 3)),
+            5
+        );
+        assert_eq!(
+            document(text, PositionEncoding::Utf32).position_to_offset(Position::new(0, 2)),
+            5
+        );
+        assert_eq!(
+            document(text, PositionEncoding::Utf8).position_to_offset(Position::new(0, 5)),
+            5
+        );
+    }
+
+    #[test]
+    fn apply_changes_replaces_full_text() {
+        let mut doc = document("old\n", PositionEncoding::Utf16);
+        doc.apply_changes(vec![change(None, "new\ntext")], 2);
+        assert_eq!(doc.lines().collect::<Vec<_>>(), ["new", "text"]);
+        assert_eq!(doc.version(), 2);
+    }
+
+    #[test]
+    fn apply_changes_applies_ranges_in_order() {
+        let mut doc = document("int x;\nint y;\n", PositionEncoding::Utf16);
+        doc.apply_changes(
+            vec![
+                change(Some(((0, 4), (0, 5))), "foo"),
+                change(Some(((1, 0), (1, 0))), "// "),
+                change(Some(((0, 8), (0, 8))), "\n"),
+            ],
+            3,
+        );
+        assert_eq!(
+            doc.lines().collect::<Vec<_>>(),
+            ["int foo;", "", "// int y;", ""]
+        );
+    }
+
+    #[test]
+    fn apply_changes_keeps_crlf_terminators() {
+        let mut doc = document("ab\r\ncd\r\n", PositionEncoding::Utf16);
+        doc.apply_changes(vec![change(Some(((0, 1), (1, 1))), "X")], 2);
+        assert_eq!(doc.text, "aXd\r\n");
+        assert_eq!(doc.lines().collect::<Vec<_>>(), ["aXd", ""]);
+    }
+
+    #[test]
+    fn apply_changes_clamps_out_of_range_positions() {
+        let mut doc = document("ab\ncd", PositionEncoding::Utf16);
+        doc.apply_changes(vec![change(Some(((0, 9), (0, 12))), "!")], 2);
+        assert_eq!(doc.text, "ab!\ncd");
+        doc.apply_changes(vec![change(Some(((5, 0), (6, 0))), "\nef")], 3);
+        assert_eq!(doc.text, "ab!\ncd\nef");
+        // A reversed range inserts at its start instead of panicking
+        doc.apply_changes(vec![change(Some(((1, 2), (1, 0))), "?")], 4);
+        assert_eq!(doc.text, "ab!\ncd?\nef");
     }
 }
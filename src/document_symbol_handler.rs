@@ -0,0 +1,230 @@
+use std::{collections::HashMap, io};
+
+use lsp_server::{Message, Request, Response};
+use lsp_types::{DocumentSymbol, DocumentSymbolParams, DocumentSymbolResponse, Position, Range};
+
+use crate::{
+    cancellation::CancellationToken,
+    ctags::{CtagsEntry, CtagsHandler},
+    goto_handler::{locate_tags, read_lines},
+    logger::Logger,
+    lsp_error::LspError,
+    position_encoding::PositionEncoding,
+    LspServer,
+};
+
+pub struct DocumentSymbolHandler;
+
+impl DocumentSymbolHandler {
+    /// Splits a scope name such as `ns::Foo` or `pkg.Foo` into its parent scope
+    /// and its last component.
+    fn split_scope(scope: &str) -> (&str, &str) {
+        scope
+            .rsplit_once("::")
+            .or_else(|| scope.rsplit_once('.'))
+            .unwrap_or(("", scope))
+    }
+
+    /// Builds the symbol of `entry` found at `line`, whose text is taken from
+    /// `lines`. The selection range always lies inside the full range, even
+    /// when the file no longer has the line.
+    fn to_symbol(
+        entry: &CtagsEntry,
+        line: u32,
+        lines: &[String],
+        encoding: PositionEncoding,
+    ) -> DocumentSymbol {
+        let text_line = |line: u32| lines.get(line as usize);
+        let end_line = entry
+            .end
+            .map_or(line, |end| end.saturating_sub(1).max(line));
+        let end_character = text_line(end_line).map_or(0, |text| encoding.column(text, text.len()));
+        let selection_range = text_line(line)
+            .and_then(|text| {
+                let start = text.find(&entry.name)?;
+                Some(encoding.range(line, text, start, start + entry.name.len()))
+            })
+            .unwrap_or_else(|| encoding.range(line, &entry.name, 0, entry.name.len()));
+        let end = Position {
+            line: end_line,
+            character: end_character,
+        }
+        .max(selection_range.end);
+
+        #[allow(deprecated)]
+        DocumentSymbol {
+            name: entry.name.clone(),
+            detail: entry.detail(),
+            kind: entry.symbol_kind(),
+            tags: None,
+            deprecated: None,
+            range: Range {
+                start: Position { line, character: 0 },
+                end,
+            },
+            selection_range,
+            children: None,
+        }
+    }
+
+    /// Finds the parent of every symbol by range containment. This relies on
+    /// the `end:` field to give the enclosing symbols a real extent.
+    fn parents_by_range(symbols: &[DocumentSymbol]) -> Vec<Option<usize>> {
+        let mut order: Vec<usize> = (0..symbols.len()).collect();
+        order.sort_by(|&a, &b| {
+            let (a, b) = (&symbols[a].range, &symbols[b].range);
+            a.start
+                .line
+                .cmp(&b.start.line)
+                .then(b.end.line.cmp(&a.end.line))
+        });
+
+        let mut parents = vec![None; symbols.len()];
+        let mut stack: Vec<usize> = Vec::new();
+        for idx in order {
+            let range = &symbols[idx].range;
+            while let Some(&top) = stack.last() {
+                let parent = &symbols[top].range;
+                if parent.end.line >= range.end.line && parent.end.line > parent.start.line {
+                    break;
+                }
+                stack.pop();
+            }
+            parents[idx] = stack.last().copied();
+            stack.push(idx);
+        }
+        parents
+    }
+
+    /// Finds the parent of every symbol by matching its `scope` field against
+    /// the scope and name of the other symbols.
+    fn parents_by_scope(entries: &[CtagsEntry]) -> Vec<Option<usize>> {
+        let mut by_name: HashMap<(&str, &str), usize> = HashMap::new();
+        for (idx, entry) in entries.iter().enumerate() {
+            let scope = entry.scope.as_ref().map_or("", |scope| scope.name.as_str());
+            by_name.entry((scope, &entry.name)).or_insert(idx);
+        }
+
+        entries
+            .iter()
+            .enumerate()
+            .map(|(idx, entry)| {
+                let scope = entry.scope.as_ref()?;
+                let parent = *by_name.get(&DocumentSymbolHandler::split_scope(&scope.name))?;
+                (parent != idx).then_some(parent)
+            })
+            .collect()
+    }
+
+    fn build_tree(
+        symbols: Vec<DocumentSymbol>,
+        parents: Vec<Option<usize>>,
+    ) -> Vec<DocumentSymbol> {
+        let mut children: Vec<Vec<usize>> = vec![Vec::new(); symbols.len()];
+        let mut roots = Vec::new();
+        for (idx, parent) in parents.iter().enumerate() {
+            match parent {
+                Some(parent) => children[*parent].push(idx),
+                None => roots.push(idx),
+            }
+        }
+
+        fn take(
+            idx: usize,
+            symbols: &mut Vec<Option<DocumentSymbol>>,
+            children: &[Vec<usize>],
+        ) -> Option<DocumentSymbol> {
+            let mut symbol = symbols[idx].take()?;
+            if !children[idx].is_empty() {
+                symbol.children = Some(
+                    children[idx]
+                        .iter()
+                        .filter_map(|&child| take(child, symbols, children))
+                        .collect(),
+                );
+            }
+            Some(symbol)
+        }
+
+        let mut symbols: Vec<Option<DocumentSymbol>> = symbols.into_iter().map(Some).collect();
+        roots
+            .into_iter()
+            .filter_map(|idx| take(idx, &mut symbols, &children))
+            .collect()
+    }
+
+    fn document_symbols(
+        &self,
+        server: &LspServer,
+        params: DocumentSymbolParams,
+        cancel: &CancellationToken,
+    ) -> io::Result<Vec<DocumentSymbol>> {
+        let uri = params.text_document.uri;
+        let path = uri
+            .to_file_path()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Not a file URI"))?;
+
+        let document = server.documents.read().unwrap().get(&uri);
+        let snapshot = server.workspace_manager.read().unwrap().snapshot();
+        let entries = CtagsHandler::query_file_ctags(&snapshot.workspaces, &path)?;
+        let encoding = snapshot.config.position_encoding;
+
+        // Tags without a `line:` field need their pattern looked up in the file,
+        // and so do all of them while unsaved edits may have moved them.
+        let needs_lookup = |entry: &CtagsEntry| document.is_some() || entry.line.is_none();
+        let unresolved: Vec<CtagsEntry> = entries
+            .iter()
+            .filter(|entry| needs_lookup(entry))
+            .cloned()
+            .collect();
+        let mut resolved =
+            locate_tags(&unresolved, &server.documents, encoding, cancel)?.into_iter();
+
+        let mut located = Vec::new();
+        for entry in entries {
+            let line = match entry.line {
+                Some(line) if !needs_lookup(&entry) => line.checked_sub(1),
+                _ => resolved
+                    .next()
+                    .flatten()
+                    .map(|tag| tag.location.range.start.line),
+            };
+            if let Some(line) = line {
+                located.push((entry, line));
+            }
+        }
+        located.sort_by_key(|(_, line)| *line);
+
+        // Files that vanished since tagging still list their symbols
+        let lines = read_lines(&path.display().to_string(), &server.documents).unwrap_or_default();
+        let symbols: Vec<DocumentSymbol> = located
+            .iter()
+            .map(|(entry, line)| DocumentSymbolHandler::to_symbol(entry, *line, &lines, encoding))
+            .collect();
+        let entries: Vec<CtagsEntry> = located.into_iter().map(|(entry, _)| entry).collect();
+
+        let parents = if entries.iter().any(|entry| entry.end.is_some()) {
+            DocumentSymbolHandler::parents_by_range(&symbols)
+        } else if entries.iter().any(|entry| entry.scope.is_some()) {
+            DocumentSymbolHandler::parents_by_scope(&entries)
+        } else {
+            vec![None; symbols.len()]
+        };
+        Ok(DocumentSymbolHandler::build_tree(symbols, parents))
+    }
+
+    pub fn handle(&self, req: Request, server: &LspServer) -> Result<(), LspError> {
+        Logger::info(&format!("Received request: {:?}", req.method));
+        let params: DocumentSymbolParams = serde_json::from_value(req.params)?;
+
+        let cancel = server.cancellation_token(&req.id);
+        let symbols = self.document_symbols(server, params, &cancel)?;
+        let resp = Response::new_ok(req.id.clone(), DocumentSymbolResponse::Nested(symbols));
+        server
+            .connection
+            .sender
+            .send(Message::Response(resp))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+}
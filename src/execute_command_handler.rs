@@ -0,0 +1,129 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use lsp_server::{Message, Notification, Request, Response};
+use lsp_types::{ExecuteCommandParams, MessageType, ShowMessageParams, Url, WorkspaceFolder};
+
+use crate::{
+    logger::Logger,
+    lsp_error::LspError,
+    tag_generator::{TagGenerator, GENERATE_TAGS_COMMAND, REFRESH_TAGS_COMMAND},
+    LspServer,
+};
+
+pub struct ExecuteCommandHandler;
+
+impl ExecuteCommandHandler {
+    /// The workspaces named by the command arguments, or all of them when no
+    /// folder URI is given.
+    fn target_folders(server: &LspServer, arguments: &[serde_json::Value]) -> Vec<WorkspaceFolder> {
+        let uris: Vec<Url> = arguments
+            .iter()
+            .filter_map(|argument| serde_json::from_value(argument.clone()).ok())
+            .collect();
+        let manager = server.workspace_manager.read().unwrap();
+        manager
+            .workspaces
+            .iter()
+            .filter(|workspace| uris.is_empty() || uris.contains(&workspace.folder.uri))
+            .map(|workspace| workspace.folder.clone())
+            .collect()
+    }
+
+    /// The tags files named by the command arguments with the workspace each
+    /// belongs to and whether the server generated it. Only tags files the
+    /// workspaces already use are accepted.
+    fn target_tag_files(
+        server: &LspServer,
+        arguments: &[serde_json::Value],
+    ) -> Result<Vec<(WorkspaceFolder, PathBuf, bool)>, LspError> {
+        let manager = server.workspace_manager.read().unwrap();
+        arguments
+            .iter()
+            .map(|argument| {
+                let path: PathBuf = serde_json::from_value(argument.clone())?;
+                manager
+                    .workspaces
+                    .iter()
+                    .find(|workspace| {
+                        workspace
+                            .tag_files
+                            .iter()
+                            .any(|tag_file| Path::new(&tag_file.path) == path)
+                    })
+                    .map(|workspace| {
+                        let generated = workspace.folder.uri.to_file_path().is_ok_and(|folder| {
+                            TagGenerator::is_generated(&manager.config.ctags, &folder, &path)
+                        });
+                        (workspace.folder.clone(), path.clone(), generated)
+                    })
+                    .ok_or_else(|| {
+                        LspError::InvalidParams(format!("Not a tags file: {}", path.display()))
+                    })
+            })
+            .collect()
+    }
+
+    /// Tells the user that `tag_file` was not generated by the server, so it
+    /// is left alone: regenerating it with the default ctags options could
+    /// drop tags the user's own options produce.
+    fn report_user_tag_file(server: &LspServer, tag_file: &Path) {
+        let params = ShowMessageParams {
+            typ: MessageType::INFO,
+            message: format!(
+                "The tags file {} is not generated by ctags_ls. Regenerate it with the tool that built it.",
+                tag_file.display()
+            ),
+        };
+        if let Err(e) = server
+            .connection
+            .sender
+            .send(Message::Notification(Notification::new(
+                "window/showMessage".to_string(),
+                params,
+            )))
+        {
+            Logger::error(&format!("Failed to report tags file: {:?}", e));
+        }
+    }
+
+    pub fn handle(&self, req: Request, server: &LspServer) -> Result<(), LspError> {
+        Logger::info(&format!("Received request: {:?}", req.method));
+        let params: ExecuteCommandParams = serde_json::from_value(req.params)?;
+
+        let resp = match params.command.as_str() {
+            GENERATE_TAGS_COMMAND => {
+                for folder in ExecuteCommandHandler::target_folders(server, &params.arguments) {
+                    TagGenerator::spawn(server, folder);
+                }
+                Response::new_ok(req.id.clone(), serde_json::Value::Null)
+            }
+            REFRESH_TAGS_COMMAND => {
+                for (folder, tag_file, generated) in
+                    ExecuteCommandHandler::target_tag_files(server, &params.arguments)?
+                {
+                    if generated {
+                        TagGenerator::refresh(server, folder, tag_file);
+                    } else {
+                        ExecuteCommandHandler::report_user_tag_file(server, &tag_file);
+                    }
+                }
+                Response::new_ok(req.id.clone(), serde_json::Value::Null)
+            }
+            command => {
+                return Err(LspError::InvalidParams(format!(
+                    "Unknown command: {}",
+                    command
+                )));
+            }
+        };
+        server
+            .connection
+            .sender
+            .send(Message::Response(resp))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use lsp_server::{Message, Request, RequestId};
+use lsp_types::{
+    DidChangeWatchedFilesRegistrationOptions, FileChangeType, FileSystemWatcher, Registration,
+    RegistrationParams, Url, WorkspaceFoldersChangeEvent,
+};
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecursiveMode, Watcher,
+};
+
+use crate::{
+    ctags::CtagsHandler, document::DocumentsCache, logger::Logger, tag_diagnostics::TagDiagnostics,
+    tag_generator::TagGenerator, workspace::WorkspaceManager, LspServer,
+};
+
+/// Id of the `workspace/didChangeWatchedFiles` registration.
+const WATCHED_FILES_REGISTRATION: &str = "ctags_ls/watchedFiles";
+/// How long changed sources must stay untouched before they are re-tagged.
+const RETAG_DEBOUNCE: Duration = Duration::from_millis(300);
+/// The most files re-tagged by a single ctags run.
+const RETAG_BATCH_SIZE: usize = 256;
+
+pub struct FileWatcher;
+
+impl FileWatcher {
+    /// Asks the client to report changed files when it can register watchers
+    /// dynamically. Otherwise the workspace folders are watched by the server.
+    pub fn start(server: &LspServer) {
+        let dynamic_registration = server
+            .client_capabilities
+            .read()
+            .unwrap()
+            .workspace
+            .as_ref()
+            .and_then(|workspace| workspace.did_change_watched_files.as_ref())
+            .and_then(|watched_files| watched_files.dynamic_registration)
+            .unwrap_or(false);
+        if dynamic_registration {
+            FileWatcher::register(server);
+        } else {
+            FileWatcher::watch_folders(server);
+        }
+    }
+
+    fn register(server: &LspServer) {
+        let mut watchers = vec![FileSystemWatcher {
+            glob_pattern: "**/*".to_string(),
+            kind: None,
+        }];
+        // Tags files outside the workspace folders need their own watchers
+        let manager = server.workspace_manager.read().unwrap();
+        watchers.extend(
+            manager
+                .config
+                .tag_file_patterns
+                .iter()
+                .filter(|pattern| Path::new(pattern.pattern()).is_absolute())
+                .map(|pattern| FileSystemWatcher {
+                    glob_pattern: pattern.pattern().to_string(),
+                    kind: None,
+                }),
+        );
+
+        let registration = Registration {
+            id: WATCHED_FILES_REGISTRATION.to_string(),
+            method: "workspace/didChangeWatchedFiles".to_string(),
+            register_options: serde_json::to_value(DidChangeWatchedFilesRegistrationOptions {
+                watchers,
+            })
+            .ok(),
+        };
+        let request = Request::new(
+            RequestId::from(WATCHED_FILES_REGISTRATION.to_string()),
+            "client/registerCapability".to_string(),
+            RegistrationParams {
+                registrations: vec![registration],
+            },
+        );
+        if let Err(e) = server.connection.sender.send(Message::Request(request)) {
+            Logger::error(&format!("Failed to register file watchers: {:?}", e));
+        }
+    }
+
+    fn watch_folders(server: &LspServer) {
+        let changes = FileWatcher::changes(server).clone();
+        let watcher =
+            notify::recommended_watcher(move |result: notify::Result<Event>| match result {
+                Ok(event) => changes.apply(FileWatcher::to_changes(event)),
+                Err(e) => Logger::error(&format!("File watcher error: {:?}", e)),
+            });
+        let mut watcher = match watcher {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                Logger::error(&format!("Failed to start file watcher: {:?}", e));
+                return;
+            }
+        };
+
+        let folders: Vec<PathBuf> = server
+            .workspace_manager
+            .read()
+            .unwrap()
+            .workspaces
+            .iter()
+            .filter_map(|workspace| workspace.folder.uri.to_file_path().ok())
+            .collect();
+        for folder in folders {
+            FileWatcher::watch(&mut watcher, &folder);
+        }
+        *server.watcher.lock().unwrap() = Some(watcher);
+    }
+
+    fn watch(watcher: &mut impl Watcher, folder: &Path) {
+        match watcher.watch(folder, RecursiveMode::Recursive) {
+            Ok(()) => Logger::info(&format!("Watching {:?}", folder)),
+            Err(e) => Logger::error(&format!("Failed to watch {:?}: {:?}", folder, e)),
+        }
+    }
+
+    /// Keeps the server-side watcher in line with the workspace folders. Files
+    /// watched by the client need nothing, since its watchers are global.
+    pub fn update_folders(server: &LspServer, event: &WorkspaceFoldersChangeEvent) {
+        let mut watcher = server.watcher.lock().unwrap();
+        let Some(watcher) = watcher.as_mut() else {
+            return;
+        };
+        for folder in &event.removed {
+            if let Ok(path) = folder.uri.to_file_path() {
+                let _ = watcher.unwatch(&path);
+            }
+        }
+        for folder in &event.added {
+            if let Ok(path) = folder.uri.to_file_path() {
+                FileWatcher::watch(watcher, &path);
+            }
+        }
+    }
+
+    fn to_changes(event: Event) -> Vec<(PathBuf, FileChangeType)> {
+        let typ = match event.kind {
+            EventKind::Create(_) => FileChangeType::CREATED,
+            EventKind::Remove(_) => FileChangeType::DELETED,
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => FileChangeType::DELETED,
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => FileChangeType::CREATED,
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                let mut paths = event.paths.into_iter();
+                return paths
+                    .next()
+                    .map(|from| (from, FileChangeType::DELETED))
+                    .into_iter()
+                    .chain(paths.map(|to| (to, FileChangeType::CREATED)))
+                    .collect();
+            }
+            EventKind::Modify(ModifyKind::Metadata(_)) => return Vec::new(),
+            EventKind::Modify(_) => FileChangeType::CHANGED,
+            _ => return Vec::new(),
+        };
+        event.paths.into_iter().map(|path| (path, typ)).collect()
+    }
+
+    /// The changes handler of `server`, starting its re-tagging worker on
+    /// first use.
+    pub fn changes(server: &LspServer) -> &FileChanges {
+        server.file_changes.get_or_init(|| FileChanges::new(server))
+    }
+}
+
+/// Applies files changed outside the editor. Sources are re-tagged by a single
+/// worker, which waits for changes to settle and re-tags them in batches.
+#[derive(Clone)]
+pub struct FileChanges {
+    workspace_manager: Arc<RwLock<WorkspaceManager>>,
+    documents: Arc<RwLock<DocumentsCache>>,
+    diagnostics: TagDiagnostics,
+    retag_queue: crossbeam_channel::Sender<(PathBuf, FileChangeType)>,
+}
+
+impl FileChanges {
+    fn new(server: &LspServer) -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let workspace_manager = server.workspace_manager.clone();
+        let diagnostics = TagDiagnostics::new(server);
+        thread::spawn(move || {
+            FileChanges::retag_worker(&workspace_manager, &diagnostics, receiver)
+        });
+        Self {
+            workspace_manager: server.workspace_manager.clone(),
+            documents: server.documents.clone(),
+            diagnostics: TagDiagnostics::new(server),
+            retag_queue: sender,
+        }
+    }
+
+    /// Reacts to files changed outside the editor. Changed tags files make the
+    /// workspaces resolve their tags files again, while changed and deleted
+    /// sources are queued for the re-tagging worker. Open documents are left
+    /// to `didSave`.
+    pub fn apply(&self, changes: Vec<(PathBuf, FileChangeType)>) {
+        if changes.is_empty() {
+            return;
+        }
+        let matcher = self.workspace_manager.read().unwrap().path_matcher();
+        let (tag_changes, source_changes): (Vec<_>, Vec<_>) = changes
+            .into_iter()
+            .partition(|(path, _)| matcher.is_tag_file(path));
+        let source_changes: Vec<_> = source_changes
+            .into_iter()
+            .filter(|(path, _)| {
+                // Tags files being written are no sources
+                path.extension().is_none_or(|ext| ext != "partial")
+                    && !matcher.is_excluded(path)
+                    && matcher.contains(path)
+            })
+            .collect();
+        if !tag_changes.is_empty() {
+            let paths: Vec<PathBuf> = tag_changes.into_iter().map(|(path, _)| path).collect();
+            Logger::info(&format!("Tags files changed: {:?}", paths));
+            self.workspace_manager
+                .write()
+                .unwrap()
+                .refresh_tag_files(&paths);
+            self.diagnostics.refresh();
+        }
+
+        let documents = self.documents.read().unwrap();
+        for (path, typ) in source_changes {
+            let open = Url::from_file_path(&path).is_ok_and(|uri| documents.get(&uri).is_some());
+            if open && typ != FileChangeType::DELETED {
+                continue;
+            }
+            if self.retag_queue.send((path, typ)).is_err() {
+                Logger::error("The re-tagging worker has stopped");
+                return;
+            }
+        }
+    }
+
+    /// Re-tags the queued sources until the queue is closed. Changes are
+    /// collected until none came in for [`RETAG_DEBOUNCE`], or until a batch
+    /// holds [`RETAG_BATCH_SIZE`] files, and each batch is tagged by one ctags
+    /// run.
+    fn retag_worker(
+        workspace_manager: &Arc<RwLock<WorkspaceManager>>,
+        diagnostics: &TagDiagnostics,
+        receiver: crossbeam_channel::Receiver<(PathBuf, FileChangeType)>,
+    ) {
+        while let Ok((path, typ)) = receiver.recv() {
+            let mut batch = BTreeMap::from([(path, typ)]);
+            while batch.len() < RETAG_BATCH_SIZE {
+                match receiver.recv_timeout(RETAG_DEBOUNCE) {
+                    Ok((path, typ)) => {
+                        batch.insert(path, typ);
+                    }
+                    Err(_) => break,
+                }
+            }
+            FileChanges::retag_batch(workspace_manager, diagnostics, batch);
+        }
+    }
+
+    /// Re-tags the changed sources of `batch` and drops the tags of the
+    /// deleted ones. Only files the tags already index, or files sharing an
+    /// extension with them, are sources worth tagging.
+    fn retag_batch(
+        workspace_manager: &Arc<RwLock<WorkspaceManager>>,
+        diagnostics: &TagDiagnostics,
+        batch: BTreeMap<PathBuf, FileChangeType>,
+    ) {
+        let snapshot = workspace_manager.read().unwrap().snapshot();
+        let indexed: HashSet<PathBuf> = match CtagsHandler::indexed_files(&snapshot.workspaces) {
+            Ok(files) => files.into_iter().map(PathBuf::from).collect(),
+            Err(e) => {
+                Logger::error(&format!("Failed to list indexed files: {:?}", e));
+                return;
+            }
+        };
+        let extensions: HashSet<&OsStr> =
+            indexed.iter().filter_map(|file| file.extension()).collect();
+
+        let mut retag = Vec::new();
+        let mut deleted = Vec::new();
+        for (path, typ) in batch {
+            if typ == FileChangeType::DELETED || !path.is_file() {
+                if indexed.contains(&path) {
+                    deleted.push(path);
+                }
+            } else if indexed.contains(&path)
+                || path.extension().is_some_and(|ext| extensions.contains(ext))
+            {
+                retag.push(path);
+            }
+        }
+
+        if !deleted.is_empty() {
+            let mut manager = workspace_manager.write().unwrap();
+            for path in &deleted {
+                if let Some(workspace) = manager.workspace_for_mut(path) {
+                    workspace
+                        .overlay
+                        .insert(path.display().to_string(), Vec::new());
+                }
+            }
+        }
+        if !retag.is_empty() {
+            Logger::info(&format!("Re-tagging {} changed files", retag.len()));
+            TagGenerator::retag_files(workspace_manager, &retag, diagnostics);
+        }
+    }
+}
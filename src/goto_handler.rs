@@ -1,69 +1,206 @@
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{self, BufRead},
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::PathBuf,
+    sync::RwLock,
+    time::SystemTime,
 };
 
-use lsp_server::{Message, Request, Response};
-use lsp_types::{GotoDefinitionParams, GotoDefinitionResponse, Location, Position, Range, Url};
+use lsp_server::{Message, Notification, Request, Response};
+use lsp_types::{
+    GotoDefinitionParams, GotoDefinitionResponse, Location, MessageType, ShowMessageParams, Url,
+};
 
 use crate::{
+    cancellation::CancellationToken,
     ctags::{CtagsEntry, CtagsHandler},
-    document::DocumentsCache,
+    document::{DocumentsCache, TextDocument},
     logger::Logger,
+    lsp_error::LspError,
+    position_encoding::PositionEncoding,
+    scope_context::ScopeContext,
+    tag_locator::{LocateStrategy, TagLocator},
 };
 use crate::{workspace::Workspace, LspServer};
 
-fn find_tags_location(entries: &Vec<CtagsEntry>, locations: &mut Vec<Location>) -> io::Result<()> {
+/// A tag resolved to a place in its file.
+#[derive(Debug, Clone)]
+pub struct LocatedTag {
+    pub location: Location,
+    pub strategy: LocateStrategy,
+    /// Whether the tags file is out of date for this tag.
+    pub stale: bool,
+}
+
+fn find_tags_location(
+    entries: &[CtagsEntry],
+    locations: &mut Vec<LocatedTag>,
+    documents: &RwLock<DocumentsCache>,
+    encoding: PositionEncoding,
+    cancel: &CancellationToken,
+) -> io::Result<()> {
+    locations.extend(
+        locate_tags(entries, documents, encoding, cancel)?
+            .into_iter()
+            .flatten(),
+    );
+    Ok(())
+}
+
+/// Returns the lines of the file at `file_path`, taken from its open document
+/// when there is one so that unsaved edits are accounted for. Files in another
+/// encoding than UTF-8 are read with their invalid bytes replaced.
+pub fn read_lines(file_path: &str, documents: &RwLock<DocumentsCache>) -> io::Result<Vec<String>> {
+    let document = Url::from_file_path(file_path)
+        .ok()
+        .and_then(|uri| documents.read().unwrap().get(&uri));
+    match document {
+        Some(document) => Ok(document.lines().map(str::to_string).collect()),
+        None => Ok(String::from_utf8_lossy(&fs::read(file_path)?)
+            .lines()
+            .map(str::to_string)
+            .collect()),
+    }
+}
+
+/// Resolves the location of every entry, keeping the order of `entries`, with
+/// columns counted in `encoding`. Open files are searched as they are in the
+/// editor. Entries that cannot be found in their file, or whose file cannot be
+/// read, are `None`. Stops with an error once `cancel` is cancelled.
+pub fn locate_tags(
+    entries: &[CtagsEntry],
+    documents: &RwLock<DocumentsCache>,
+    encoding: PositionEncoding,
+    cancel: &CancellationToken,
+) -> io::Result<Vec<Option<LocatedTag>>> {
     // Group entries by file to minimize file reads
-    let mut file_to_entries: HashMap<String, Vec<&CtagsEntry>> = HashMap::new();
-    for entry in entries {
-        file_to_entries
-            .entry(entry.file.clone())
-            .or_default()
-            .push(entry);
+    let mut file_to_entries: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (idx, entry) in entries.iter().enumerate() {
+        file_to_entries.entry(&entry.file).or_default().push(idx);
     }
 
-    for (file_path, entries) in file_to_entries {
-        let file = File::open(&file_path)?;
-        let reader = io::BufReader::new(file);
+    // Resolved locations are kept per entry so the results follow the order of `entries`
+    let mut found: Vec<Option<LocatedTag>> = vec![None; entries.len()];
+    for (file_path, indices) in file_to_entries {
+        cancel.check()?;
+        // Tags naming relative or invalid paths have no location to go to
+        let Ok(uri) = Url::from_file_path(file_path) else {
+            continue;
+        };
+        // Files removed since they were tagged leave their tags unresolved
+        let lines = match read_lines(file_path, documents) {
+            Ok(lines) => lines,
+            Err(e) => {
+                Logger::error(&format!("Failed to read {}: {}", file_path, e));
+                continue;
+            }
+        };
 
-        let mut found = vec![false; entries.len()];
-        for (line_num, line) in reader.lines().enumerate() {
-            let line = line?;
-            for (idx, entry) in entries.iter().enumerate() {
-                if found[idx] {
-                    continue;
+        for idx in indices {
+            let entry = &entries[idx];
+            found[idx] = TagLocator::locate(entry, &lines).map(|tag_match| {
+                if tag_match.stale {
+                    Logger::info(&format!(
+                        "Stale tag {} in {} located by {}",
+                        entry.name,
+                        entry.file,
+                        tag_match.strategy.description()
+                    ));
                 }
-                if line.contains(&entry.pattern) {
-                    if let Some(character) = line.find(&entry.name) {
-                        locations.push(Location {
-                            uri: Url::parse(&format!("file://{}", entry.file))
-                                .expect("Failed to parse URL"),
-                            range: Range {
-                                start: Position {
-                                    line: line_num as u32,
-                                    character: character as u32,
-                                },
-                                end: Position {
-                                    line: line_num as u32,
-                                    character: (character + entry.name.len()) as u32,
-                                },
-                            },
-                        });
-                        found[idx] = true;
-                        break;
-                    }
+                LocatedTag {
+                    location: Location {
+                        uri: uri.clone(),
+                        range: encoding.range(
+                            tag_match.line as u32,
+                            &lines[tag_match.line],
+                            tag_match.column,
+                            tag_match.column + entry.name.len(),
+                        ),
+                    },
+                    strategy: tag_match.strategy,
+                    stale: tag_match.stale,
                 }
-            }
-
-            if found.iter().all(|&f| f) {
-                break;
-            }
+            });
         }
     }
 
-    Ok(())
+    Ok(found)
+}
+
+/// The tags files that may hold the tags of `located`, with the time they
+/// were last written: those of the workspaces containing their files, or of
+/// every workspace for files outside of them.
+fn stale_tag_files(
+    workspaces: &[Workspace],
+    located: &[&LocatedTag],
+) -> HashSet<(PathBuf, SystemTime)> {
+    let paths: Vec<PathBuf> = located
+        .iter()
+        .filter_map(|tag| tag.location.uri.to_file_path().ok())
+        .collect();
+    let containing: Vec<&Workspace> = workspaces
+        .iter()
+        .filter(|w| paths.iter().any(|path| w.contains(path)))
+        .collect();
+    let candidates = if containing.is_empty() {
+        workspaces.iter().collect()
+    } else {
+        containing
+    };
+    candidates
+        .into_iter()
+        .flat_map(|w| &w.tag_files)
+        .filter_map(|tag_file| {
+            let modified = fs::metadata(&tag_file.path)
+                .and_then(|m| m.modified())
+                .ok()?;
+            Some((PathBuf::from(&tag_file.path), modified))
+        })
+        .collect()
+}
+
+/// Tells the user when some of `located` were only found because the file
+/// changed since it was tagged, so the tags deserve a refresh. Each tags file
+/// is reported once, until it is written again.
+fn report_stale(server: &LspServer, workspaces: &[Workspace], located: &[LocatedTag]) {
+    let stale: Vec<&LocatedTag> = located.iter().filter(|tag| tag.stale).collect();
+    let Some(first) = stale.first() else {
+        return;
+    };
+    let tag_files = stale_tag_files(workspaces, &stale);
+    {
+        let mut reported = server.stale_reported.lock().unwrap();
+        let before = reported.len();
+        reported.extend(tag_files);
+        if reported.len() == before {
+            return;
+        }
+    }
+    let file = first.location.uri.to_file_path().map_or_else(
+        |_| first.location.uri.to_string(),
+        |path| path.display().to_string(),
+    );
+    let message = format!(
+        "Tags for {} are out of date: {} of {} locations found by {}. Regenerate the tags to refresh them.",
+        file,
+        stale.len(),
+        located.len(),
+        first.strategy.description()
+    );
+    let params = ShowMessageParams {
+        typ: MessageType::WARNING,
+        message,
+    };
+    if let Err(e) = server
+        .connection
+        .sender
+        .send(Message::Notification(Notification::new(
+            "window/showMessage".to_string(),
+            params,
+        )))
+    {
+        Logger::error(&format!("Failed to report stale tags: {:?}", e));
+    }
 }
 
 pub trait GotoHandler {
@@ -71,52 +208,70 @@ pub trait GotoHandler {
 
     fn handle_goto(
         &self,
-        workspaces: &Vec<Workspace>,
+        workspaces: &[Workspace],
         params: GotoDefinitionParams,
-        documents: &DocumentsCache,
-    ) -> io::Result<GotoDefinitionResponse> {
+        document: &TextDocument,
+        documents: &RwLock<DocumentsCache>,
+        encoding: PositionEncoding,
+        cancel: &CancellationToken,
+    ) -> io::Result<Vec<LocatedTag>> {
         let position = params.text_document_position_params.position;
         let uri = params.text_document_position_params.text_document.uri;
 
-        let symbol = documents
-            .get(&uri)
-            .ok_or_else(|| {
-                Logger::error(&format!("Document not found: {:?}", uri));
-                io::Error::new(io::ErrorKind::NotFound, "Document not found")
-            })?
-            .get_symbol_at_position(position)?;
-        let entries = CtagsHandler::query_ctags(workspaces, &symbol)?;
-        let mut locations: Vec<Location> = Vec::new();
-        find_tags_location(
-            &entries
-                .into_iter()
-                .filter(|entry| self.filter(entry))
-                .collect(),
-            &mut locations,
-        )?;
+        let symbol = document.get_symbol_at_position(position)?;
+        let entries = CtagsHandler::query_ctags(workspaces, &symbol, &uri, cancel)?;
+        let mut locations: Vec<LocatedTag> = Vec::new();
+        let entries: Vec<CtagsEntry> = entries
+            .into_iter()
+            .filter(|entry| self.filter(entry))
+            .collect();
+
+        // Best matches for the scope at the cursor come first
+        let entries = if entries.len() > 1 {
+            ScopeContext::at_position(workspaces, document, &uri, position)?.rank(entries)
+        } else {
+            entries
+        };
+        find_tags_location(&entries, &mut locations, documents, encoding, cancel)?;
         Logger::info(&format!(
             "Found {} locations for symbol: {}",
             locations.len(),
             symbol
         ));
-        Ok(GotoDefinitionResponse::Array(locations))
+        Ok(locations)
     }
 
-    fn handle(&self, req: Request, server: &LspServer) -> io::Result<()> {
+    fn handle(&self, req: Request, server: &LspServer) -> Result<(), LspError> {
         Logger::info(&format!("Received request: {:?}", req.method));
-        let params: GotoDefinitionParams = serde_json::from_value(req.params)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let params: GotoDefinitionParams = serde_json::from_value(req.params)?;
 
-        let workspace_manager = server.workspace_manager.lock().unwrap();
-        let documents = server.documents.lock().unwrap();
-        let response = self.handle_goto(&workspace_manager.workspaces, params, &documents)?;
+        let uri = params
+            .text_document_position_params
+            .text_document
+            .uri
+            .clone();
+        let document = server.document(&uri)?;
+        let cancel = server.cancellation_token(&req.id);
+        let snapshot = server.workspace_manager.read().unwrap().snapshot();
+        let located = self.handle_goto(
+            &snapshot.workspaces,
+            params,
+            &document,
+            &server.documents,
+            snapshot.config.position_encoding,
+            &cancel,
+        )?;
+        server.ensure_current(&uri, &document)?;
+        report_stale(server, &snapshot.workspaces, &located);
+        let response =
+            GotoDefinitionResponse::Array(located.into_iter().map(|tag| tag.location).collect());
 
         let resp = Response::new_ok(req.id.clone(), response);
         server
             .connection
             .sender
             .send(Message::Response(resp))
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| io::Error::other(e.to_string()))?;
         Ok(())
     }
 }
@@ -0,0 +1,141 @@
+use std::{io, path::Path};
+
+use lsp_server::{Message, Request, Response};
+use lsp_types::{Hover, HoverContents, HoverParams, MarkupContent, MarkupKind};
+
+use crate::{
+    ctags::{CtagsEntry, CtagsHandler},
+    logger::Logger,
+    lsp_error::LspError,
+    scope_context::ScopeContext,
+    LspServer,
+};
+
+/// Maximum number of tags listed when a symbol has several of them.
+const MAX_LISTED_TAGS: usize = 10;
+
+pub struct HoverHandler;
+
+impl HoverHandler {
+    fn code_language(entry: &CtagsEntry) -> String {
+        match entry.language.as_deref() {
+            Some("C++") => "cpp".to_string(),
+            Some("C#") => "csharp".to_string(),
+            Some(language) => language.to_lowercase(),
+            None => Path::new(&entry.file)
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Builds `type name(signature)` from the `typeref:` and `signature:` fields.
+    fn declaration(entry: &CtagsEntry) -> String {
+        let mut declaration = String::new();
+        if let Some(typeref) = &entry.typeref {
+            declaration.push_str(&typeref.name);
+            declaration.push(' ');
+        }
+        declaration.push_str(&entry.name);
+        if let Some(signature) = &entry.signature {
+            declaration.push_str(signature);
+        }
+        declaration
+    }
+
+    fn location(entry: &CtagsEntry) -> String {
+        match entry.line {
+            Some(line) => format!("{}:{}", entry.file, line),
+            None => entry.file.clone(),
+        }
+    }
+
+    fn describe(entry: &CtagsEntry) -> String {
+        let mut value = format!("*{}*", entry.kind_name());
+        if let Some(scope) = &entry.scope {
+            value.push_str(&format!(" in {} `{}`", scope.kind, scope.name));
+        }
+        if entry.signature.is_some() || entry.typeref.is_some() {
+            value.push_str(&format!(
+                "\n\n```{}\n{}\n```",
+                HoverHandler::code_language(entry),
+                HoverHandler::declaration(entry)
+            ));
+        }
+        if let Some(pattern) = &entry.pattern {
+            value.push_str(&format!(
+                "\n\n```{}\n{}\n```",
+                HoverHandler::code_language(entry),
+                pattern.text.trim()
+            ));
+        }
+        value.push_str(&format!("\n\n{}", HoverHandler::location(entry)));
+        value
+    }
+
+    fn summarize(entries: &[CtagsEntry]) -> String {
+        let mut value = format!("{} tags for `{}`\n", entries.len(), entries[0].name);
+        for entry in entries.iter().take(MAX_LISTED_TAGS) {
+            value.push_str(&format!(
+                "\n- *{}* `{}`",
+                entry.kind_name(),
+                HoverHandler::declaration(entry)
+            ));
+            if let Some(scope) = &entry.scope {
+                value.push_str(&format!(" in `{}`", scope.name));
+            }
+            value.push_str(&format!(" — {}", HoverHandler::location(entry)));
+        }
+        if entries.len() > MAX_LISTED_TAGS {
+            value.push_str(&format!(
+                "\n- … and {} more",
+                entries.len() - MAX_LISTED_TAGS
+            ));
+        }
+        value
+    }
+
+    pub fn handle(&self, req: Request, server: &LspServer) -> Result<(), LspError> {
+        Logger::info(&format!("Received request: {:?}", req.method));
+        let params: HoverParams = serde_json::from_value(req.params)?;
+        let position = params.text_document_position_params.position;
+        let uri = params.text_document_position_params.text_document.uri;
+
+        let document = server.document(&uri)?;
+        let context = document.get_symbol_context(position)?;
+        let cancel = server.cancellation_token(&req.id);
+        let snapshot = server.workspace_manager.read().unwrap().snapshot();
+
+        let value = if context.name.is_empty() {
+            None
+        } else {
+            let workspaces = &snapshot.workspaces;
+            let entries = CtagsHandler::query_ctags(workspaces, &context.name, &uri, &cancel)?;
+            match entries.len() {
+                0 => None,
+                1 => Some(HoverHandler::describe(&entries[0])),
+                _ => {
+                    let entries = ScopeContext::at_position(workspaces, &document, &uri, position)?
+                        .rank(entries);
+                    Some(HoverHandler::summarize(&entries))
+                }
+            }
+        };
+        server.ensure_current(&uri, &document)?;
+        let hover = value.map(|value| Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: Some(context.range),
+        });
+
+        let resp = Response::new_ok(req.id.clone(), hover);
+        server
+            .connection
+            .sender
+            .send(Message::Response(resp))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+}
@@ -1,26 +1,72 @@
-use std::io;
+use std::{io, time::Duration};
 
 use lsp_server::{Message, Request, Response};
 use lsp_types::{
-    DeclarationCapability, ImplementationProviderCapability, InitializeParams, InitializeResult,
-    ServerCapabilities, TextDocumentSyncCapability, TextDocumentSyncKind, WorkspaceFolder,
+    CodeActionKind, CodeActionOptions, CodeActionProviderCapability, CompletionOptions,
+    DeclarationCapability, ExecuteCommandOptions, HoverProviderCapability,
+    ImplementationProviderCapability, InitializeParams, InitializeResult, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, TextDocumentSyncOptions,
+    TextDocumentSyncSaveOptions, WorkDoneProgressOptions, WorkspaceFolder,
 };
 
-use crate::{logger::Logger, workspace::WorkspaceManager, LspServer};
+use crate::{
+    logger::Logger,
+    lsp_error::LspError,
+    position_encoding::PositionEncoding,
+    tag_generator::{GENERATE_TAGS_COMMAND, REFRESH_TAGS_COMMAND},
+    workspace::{WorkspaceConfig, WorkspaceManager},
+    LspServer,
+};
 
 pub struct InitializeHandler;
 
 impl InitializeHandler {
-    fn initialize_workspaces(params: &InitializeParams) -> WorkspaceManager {
-        let tag_patterns = params
-            .initialization_options
-            .as_ref()
+    fn initialize_workspaces(
+        params: &InitializeParams,
+        position_encoding: PositionEncoding,
+    ) -> WorkspaceManager {
+        let options = params.initialization_options.as_ref();
+        let mut config = WorkspaceConfig {
+            position_encoding,
+            ..WorkspaceConfig::default()
+        };
+        if let Some(tag_patterns) = options
             .and_then(|options| options.get("tags"))
             .and_then(|tags| serde_json::from_value(tags.clone()).ok())
-            .unwrap_or_else(|| vec!["tags".to_string()]);
+        {
+            config.tag_file_patterns = tag_patterns;
+        }
+        if let Some(limit) = options
+            .and_then(|options| options.get("completionLimit"))
+            .and_then(|limit| limit.as_u64())
+        {
+            config.completion_limit = limit as usize;
+        }
+        if let Some(word_characters) = options
+            .and_then(|options| options.get("wordCharacters"))
+            .and_then(|chars| serde_json::from_value(chars.clone()).ok())
+        {
+            config.word_characters = word_characters;
+        }
+        if let Some(ctags) = options
+            .and_then(|options| options.get("ctags"))
+            .and_then(|ctags| serde_json::from_value(ctags.clone()).ok())
+        {
+            config.ctags = ctags;
+        }
+        if let Some(timeout) = options
+            .and_then(|options| options.get("requestTimeout"))
+            .and_then(|timeout| timeout.as_u64())
+        {
+            // Zero lets requests run for as long as they need
+            config.request_timeout = (timeout > 0).then(|| Duration::from_millis(timeout));
+        }
 
-        Logger::info(&format!("Initialize tag patterns: {:?}", tag_patterns));
-        let mut manager = WorkspaceManager::new(tag_patterns);
+        Logger::info(&format!(
+            "Initialize tag patterns: {:?}",
+            config.tag_file_patterns
+        ));
+        let mut manager = WorkspaceManager::new(config);
 
         if let Some(folders) = params.workspace_folders.as_ref() {
             for folder in folders {
@@ -36,39 +82,77 @@ impl InitializeHandler {
         manager
     }
 
-    pub fn handle(&self, req: Request, server: &LspServer) -> io::Result<()> {
+    pub fn handle(&self, req: Request, server: &LspServer) -> Result<(), LspError> {
         Logger::info("Received initialize request");
-        let params: InitializeParams = serde_json::from_value(req.params)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        // `positionEncodings` is newer than the capabilities `lsp_types` knows
+        let position_encodings: Vec<String> = req
+            .params
+            .pointer("/capabilities/general/positionEncodings")
+            .and_then(|encodings| serde_json::from_value(encodings.clone()).ok())
+            .unwrap_or_default();
+        let params: InitializeParams = serde_json::from_value(req.params)?;
 
-        let mut manager = server.workspace_manager.lock().unwrap();
-        *manager = InitializeHandler::initialize_workspaces(&params);
+        let position_encoding = PositionEncoding::negotiate(&position_encodings);
+        let mut manager = server.workspace_manager.write().unwrap();
+        *manager = InitializeHandler::initialize_workspaces(&params, position_encoding);
+        server.set_request_timeout(manager.config.request_timeout);
+        Logger::info(&format!(
+            "Negotiated position encoding: {}",
+            position_encoding.as_str()
+        ));
         Logger::info(&format!(
             "Initializing {} workspaces",
             manager.workspaces.len()
         ));
+        *server.client_capabilities.write().unwrap() = params.capabilities.clone();
 
-        let kind = TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL);
+        let sync = TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
+            open_close: Some(true),
+            change: Some(TextDocumentSyncKind::INCREMENTAL),
+            save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+            ..TextDocumentSyncOptions::default()
+        });
         let server_capabilities = ServerCapabilities::default();
         let capabilities = ServerCapabilities {
-            text_document_sync: Some(kind),
+            text_document_sync: Some(sync),
             definition_provider: Some(lsp_types::OneOf::Left(true)),
             declaration_provider: Some(DeclarationCapability::Simple(true)),
             implementation_provider: Some(ImplementationProviderCapability::Simple(true)),
+            hover_provider: Some(HoverProviderCapability::Simple(true)),
+            references_provider: Some(lsp_types::OneOf::Left(true)),
+            completion_provider: Some(CompletionOptions {
+                resolve_provider: Some(true),
+                ..CompletionOptions::default()
+            }),
+            document_symbol_provider: Some(lsp_types::OneOf::Left(true)),
+            workspace_symbol_provider: Some(lsp_types::OneOf::Left(true)),
+            code_action_provider: Some(CodeActionProviderCapability::Options(CodeActionOptions {
+                code_action_kinds: Some(vec![CodeActionKind::QUICKFIX]),
+                work_done_progress_options: WorkDoneProgressOptions::default(),
+                resolve_provider: None,
+            })),
+            execute_command_provider: Some(ExecuteCommandOptions {
+                commands: vec![
+                    GENERATE_TAGS_COMMAND.to_string(),
+                    REFRESH_TAGS_COMMAND.to_string(),
+                ],
+                ..ExecuteCommandOptions::default()
+            }),
             ..server_capabilities
         };
 
-        let initialize_result = InitializeResult {
+        let mut initialize_result = serde_json::to_value(InitializeResult {
             capabilities,
             server_info: None,
-        };
+        })?;
+        initialize_result["capabilities"]["positionEncoding"] = position_encoding.as_str().into();
 
         let resp = Response::new_ok(req.id.clone(), initialize_result);
         server
             .connection
             .sender
             .send(Message::Response(resp))
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| io::Error::other(e.to_string()))?;
         Ok(())
     }
 }
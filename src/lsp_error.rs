@@ -0,0 +1,77 @@
+use std::{fmt, io};
+
+use lsp_server::{ErrorCode, RequestId, Response};
+
+/// `RequestFailed` from LSP 3.17, which `ErrorCode` does not define.
+const REQUEST_FAILED: i32 = -32803;
+
+/// Why a request failed, as reported back to the client.
+#[derive(Debug)]
+pub enum LspError {
+    /// The parameters of the request could not be parsed or are not valid.
+    InvalidParams(String),
+    /// The request is not valid at this point of the lifecycle.
+    InvalidRequest(String),
+    /// The server does not handle the method.
+    MethodNotFound(String),
+    /// The request came before `initialize`.
+    ServerNotInitialized,
+    /// The client cancelled the request with `$/cancelRequest`.
+    RequestCancelled,
+    /// The document changed while the request ran, so its result is stale.
+    ContentModified,
+    /// The request was valid but could not be carried out.
+    RequestFailed(String),
+}
+
+impl LspError {
+    pub fn code(&self) -> i32 {
+        match self {
+            LspError::InvalidParams(_) => ErrorCode::InvalidParams as i32,
+            LspError::InvalidRequest(_) => ErrorCode::InvalidRequest as i32,
+            LspError::MethodNotFound(_) => ErrorCode::MethodNotFound as i32,
+            LspError::ServerNotInitialized => ErrorCode::ServerNotInitialized as i32,
+            LspError::RequestCancelled => ErrorCode::RequestCanceled as i32,
+            LspError::ContentModified => ErrorCode::ContentModified as i32,
+            LspError::RequestFailed(_) => REQUEST_FAILED,
+        }
+    }
+
+    pub fn to_response(&self, id: RequestId) -> Response {
+        Response::new_err(id, self.code(), self.to_string())
+    }
+}
+
+impl fmt::Display for LspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LspError::InvalidParams(message) => write!(f, "Invalid params: {}", message),
+            LspError::InvalidRequest(message) => write!(f, "Invalid request: {}", message),
+            LspError::MethodNotFound(method) => write!(f, "Method not found: {}", method),
+            LspError::ServerNotInitialized => write!(f, "Server not initialized"),
+            LspError::RequestCancelled => write!(f, "Request cancelled"),
+            LspError::ContentModified => write!(f, "Content modified"),
+            LspError::RequestFailed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<io::Error> for LspError {
+    /// Cancelled and timed out lookups stop with `Interrupted` and `TimedOut`,
+    /// and malformed input with `InvalidData`. Anything else is a failure.
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::Interrupted => LspError::RequestCancelled,
+            io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => {
+                LspError::InvalidParams(e.to_string())
+            }
+            _ => LspError::RequestFailed(e.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for LspError {
+    fn from(e: serde_json::Error) -> Self {
+        LspError::InvalidParams(e.to_string())
+    }
+}
@@ -1,15 +1,32 @@
+mod cancellation;
+mod code_action_handler;
+mod completion_handler;
 mod ctags;
 mod goto_handler;
+mod hover_handler;
 mod initialize_handler;
 mod logger;
+mod lsp_error;
+mod position_encoding;
+mod references_handler;
+mod scope_context;
 mod server;
+mod tag_diagnostics;
+mod tag_generator;
+mod tag_index;
+mod tag_locator;
+mod tags_reader;
 mod document;
+mod document_symbol_handler;
+mod execute_command_handler;
+mod file_watcher;
 mod workspace;
+mod workspace_symbol_handler;
 
 use logger::Logger;
 use lsp_server::Connection;
 use server::LspServer;
-use std::io;
+use std::{io, sync::Arc};
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
@@ -17,11 +34,13 @@ async fn main() -> io::Result<()> {
 
     Logger::info("Starting LSP server...");
 
-    let (connection, io_threads) = Connection::stdio();
+    let (connection, _io_threads) = Connection::stdio();
 
-    let server = LspServer::new(connection);
-    server.run()?;
+    let server = Arc::new(LspServer::new(connection));
+    let exit_code = server.run()?;
 
-    io_threads.join().unwrap();
-    Ok(())
+    // Neither the stdin reader nor requests still running on the blocking
+    // workers may keep the process alive after `exit`.
+    Logger::info(&format!("Exiting with code {}", exit_code));
+    std::process::exit(exit_code)
 }
@@ -0,0 +1,143 @@
+use lsp_types::{Position, Range};
+
+/// How the `character` of a position counts columns, as negotiated through
+/// `positionEncoding` during `initialize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PositionEncoding {
+    Utf8,
+    /// The only encoding clients that do not negotiate one understand.
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// Picks the first encoding of the client's `general.positionEncodings`,
+    /// which are listed by preference, falling back to UTF-16.
+    pub fn negotiate(client_encodings: &[String]) -> Self {
+        client_encodings
+            .iter()
+            .find_map(|encoding| match encoding.as_str() {
+                "utf-8" => Some(PositionEncoding::Utf8),
+                "utf-16" => Some(PositionEncoding::Utf16),
+                "utf-32" => Some(PositionEncoding::Utf32),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PositionEncoding::Utf8 => "utf-8",
+            PositionEncoding::Utf16 => "utf-16",
+            PositionEncoding::Utf32 => "utf-32",
+        }
+    }
+
+    fn units(self, c: char) -> usize {
+        match self {
+            PositionEncoding::Utf8 => c.len_utf8(),
+            PositionEncoding::Utf16 => c.len_utf16(),
+            PositionEncoding::Utf32 => 1,
+        }
+    }
+
+    /// Returns the column of the byte offset `offset` in `line`. Offsets
+    /// inside a character count up to the start of that character.
+    pub fn column(self, line: &str, offset: usize) -> u32 {
+        line.char_indices()
+            .take_while(|(start, c)| start + c.len_utf8() <= offset)
+            .map(|(_, c)| self.units(c))
+            .sum::<usize>() as u32
+    }
+
+    /// Returns the byte offset of the column `character` in `line`. Columns
+    /// past the end of the line or inside a character are clamped to the
+    /// character boundary before them.
+    pub fn offset(self, line: &str, character: u32) -> usize {
+        let mut units = 0;
+        for (offset, c) in line.char_indices() {
+            units += self.units(c);
+            if units > character as usize {
+                return offset;
+            }
+        }
+        line.len()
+    }
+
+    /// Returns the range of the bytes `start..end` of `line`, which is line
+    /// `line_number` of its document.
+    pub fn range(self, line_number: u32, line: &str, start: usize, end: usize) -> Range {
+        Range {
+            start: Position {
+                line: line_number,
+                character: self.column(line, start),
+            },
+            end: Position {
+                line: line_number,
+                character: self.column(line, end),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // U+1F600 takes four bytes, two UTF-16 code units and one UTF-32 unit
+    const LINE: &str = "a😀b";
+
+    #[test]
+    fn negotiate_picks_first_known_encoding() {
+        let encodings = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            PositionEncoding::negotiate(&encodings(&["latin1", "utf-32", "utf-8"])),
+            PositionEncoding::Utf32
+        );
+        assert_eq!(PositionEncoding::negotiate(&[]), PositionEncoding::Utf16);
+    }
+
+    #[test]
+    fn column_counts_surrogate_pairs() {
+        assert_eq!(PositionEncoding::Utf16.column(LINE, 1), 1);
+        assert_eq!(PositionEncoding::Utf16.column(LINE, 5), 3);
+        assert_eq!(PositionEncoding::Utf16.column(LINE, 6), 4);
+        assert_eq!(PositionEncoding::Utf8.column(LINE, 5), 5);
+        assert_eq!(PositionEncoding::Utf32.column(LINE, 5), 2);
+    }
+
+    #[test]
+    fn column_inside_character_counts_to_its_start() {
+        assert_eq!(PositionEncoding::Utf16.column(LINE, 3), 1);
+        assert_eq!(PositionEncoding::Utf32.column(LINE, 3), 1);
+    }
+
+    #[test]
+    fn offset_counts_surrogate_pairs() {
+        assert_eq!(PositionEncoding::Utf16.offset(LINE, 1), 1);
+        assert_eq!(PositionEncoding::Utf16.offset(LINE, 3), 5);
+        assert_eq!(PositionEncoding::Utf32.offset(LINE, 2), 5);
+        assert_eq!(PositionEncoding::Utf8.offset(LINE, 5), 5);
+    }
+
+    #[test]
+    fn offset_clamps_inside_surrogate_pair_and_past_end() {
+        // Between the two halves of the pair
+        assert_eq!(PositionEncoding::Utf16.offset(LINE, 2), 1);
+        assert_eq!(PositionEncoding::Utf8.offset(LINE, 3), 1);
+        assert_eq!(PositionEncoding::Utf16.offset(LINE, 40), LINE.len());
+    }
+
+    #[test]
+    fn range_converts_both_ends() {
+        let range = PositionEncoding::Utf16.range(3, LINE, 1, 6);
+        assert_eq!(range.start, Position::new(3, 1));
+        assert_eq!(range.end, Position::new(3, 4));
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::HashSet,
+    io,
+    sync::{Mutex, RwLock},
+    thread,
+};
+
+use lsp_server::{Message, Notification, Request, Response};
+use lsp_types::{Location, ProgressToken, ReferenceParams, Url};
+
+use crate::{
+    cancellation::CancellationToken,
+    ctags::{CtagsEntry, CtagsHandler},
+    document::{DocumentsCache, WordRules},
+    goto_handler::{locate_tags, read_lines},
+    logger::Logger,
+    lsp_error::LspError,
+    position_encoding::PositionEncoding,
+    LspServer,
+};
+
+pub struct ReferencesHandler;
+
+/// The symbol references are searched for, as a whole word of the language
+/// of the document it was found in.
+struct SymbolSearch<'a> {
+    symbol: &'a str,
+    word_rules: &'a WordRules,
+    encoding: PositionEncoding,
+}
+
+impl SymbolSearch<'_> {
+    /// Finds whole-word occurrences of the symbol in `lines`.
+    fn search_lines(&self, uri: &Url, lines: &[String]) -> Vec<Location> {
+        let SymbolSearch {
+            symbol,
+            word_rules,
+            encoding,
+        } = self;
+        let mut locations = Vec::new();
+        for (line_num, line) in lines.iter().enumerate() {
+            for (start, _) in line.match_indices(symbol) {
+                let end = start + symbol.len();
+                let before = line[..start].chars().next_back();
+                let after = line[end..].chars().next();
+                if before.is_some_and(|c| word_rules.is_word_char(c))
+                    || after.is_some_and(|c| word_rules.is_word_char(c))
+                {
+                    continue;
+                }
+                locations.push(Location {
+                    uri: uri.clone(),
+                    range: encoding.range(line_num as u32, line, start, end),
+                });
+            }
+        }
+        locations
+    }
+
+    /// Searches the file at `file`, as it is in the editor when it is open so
+    /// that the hits line up with the declarations.
+    fn search_file(&self, file: &str, documents: &RwLock<DocumentsCache>) -> Vec<Location> {
+        let Ok(uri) = Url::from_file_path(file) else {
+            return Vec::new();
+        };
+        // Files that vanished or are not text since tagging are skipped
+        match read_lines(file, documents) {
+            Ok(lines) if lines.iter().any(|line| line.contains(self.symbol)) => {
+                self.search_lines(&uri, &lines)
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl ReferencesHandler {
+    fn send_partial_result(server: &LspServer, token: &ProgressToken, locations: &[Location]) {
+        let notification = Notification::new(
+            "$/progress".to_string(),
+            serde_json::json!({ "token": token, "value": locations }),
+        );
+        if let Err(e) = server
+            .connection
+            .sender
+            .send(Message::Notification(notification))
+        {
+            Logger::error(&format!("Failed to send partial result: {:?}", e));
+        }
+    }
+
+    /// Searches `files` on all available cores. When a partial result token is
+    /// given, every batch of hits is streamed to the client as soon as a file
+    /// is done and nothing is returned.
+    fn search_files(
+        server: &LspServer,
+        files: &[String],
+        search: &SymbolSearch,
+        declarations: &HashSet<(Url, u32, u32)>,
+        token: Option<&ProgressToken>,
+        cancel: &CancellationToken,
+    ) -> io::Result<Vec<Location>> {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = files.len().div_ceil(threads).max(1);
+        let results = Mutex::new(Vec::new());
+
+        thread::scope(|scope| {
+            for chunk in files.chunks(chunk_size) {
+                let results = &results;
+                scope.spawn(move || {
+                    for file in chunk {
+                        if cancel.check().is_err() {
+                            return;
+                        }
+                        let locations: Vec<Location> = search
+                            .search_file(file, &server.documents)
+                            .into_iter()
+                            .filter(|location| {
+                                let start = location.range.start;
+                                !declarations.contains(&(
+                                    location.uri.clone(),
+                                    start.line,
+                                    start.character,
+                                ))
+                            })
+                            .collect();
+                        if locations.is_empty() {
+                            continue;
+                        }
+                        match token {
+                            Some(token) => {
+                                ReferencesHandler::send_partial_result(server, token, &locations)
+                            }
+                            None => results.lock().unwrap().extend(locations),
+                        }
+                    }
+                });
+            }
+        });
+
+        cancel.check()?;
+        Ok(results.into_inner().unwrap())
+    }
+
+    pub fn handle(&self, req: Request, server: &LspServer) -> Result<(), LspError> {
+        Logger::info(&format!("Received request: {:?}", req.method));
+        let params: ReferenceParams = serde_json::from_value(req.params)?;
+        let position = params.text_document_position.position;
+        let uri = params.text_document_position.text_document.uri;
+        let cancel = server.cancellation_token(&req.id);
+
+        let (symbol, word_rules, files, declarations, encoding) = {
+            // The symbol is searched as a word of the language it was found in
+            let document = server.document(&uri)?;
+            let symbol = document.get_symbol_at_position(position)?;
+            let word_rules = document.word_rules().clone();
+            let snapshot = server.workspace_manager.read().unwrap().snapshot();
+
+            let files = CtagsHandler::indexed_files(&snapshot.workspaces)?;
+            let encoding = snapshot.config.position_encoding;
+
+            // Hits on the tag locations of the symbol are its declarations
+            let mut declarations = HashSet::new();
+            if !params.context.include_declaration && !symbol.is_empty() {
+                let entries: Vec<CtagsEntry> =
+                    CtagsHandler::query_ctags(&snapshot.workspaces, &symbol, &uri, &cancel)?;
+                declarations = locate_tags(&entries, &server.documents, encoding, &cancel)?
+                    .into_iter()
+                    .flatten()
+                    .map(|tag| {
+                        let start = tag.location.range.start;
+                        (tag.location.uri, start.line, start.character)
+                    })
+                    .collect();
+            }
+            (symbol, word_rules, files, declarations, encoding)
+        };
+
+        let locations = if symbol.is_empty() {
+            Vec::new()
+        } else {
+            ReferencesHandler::search_files(
+                server,
+                &files,
+                &SymbolSearch {
+                    symbol: &symbol,
+                    word_rules: &word_rules,
+                    encoding,
+                },
+                &declarations,
+                params.partial_result_params.partial_result_token.as_ref(),
+                &cancel,
+            )?
+        };
+        Logger::info(&format!(
+            "Found {} references for symbol: {} in {} files",
+            locations.len(),
+            symbol,
+            files.len()
+        ));
+
+        let resp = Response::new_ok(req.id.clone(), locations);
+        server
+            .connection
+            .sender
+            .send(Message::Response(resp))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+}
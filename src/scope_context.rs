@@ -0,0 +1,153 @@
+use std::{cmp::Reverse, io};
+
+use lsp_types::{Position, Url};
+
+use crate::{
+    ctags::{CtagsEntry, CtagsHandler},
+    document::TextDocument,
+    workspace::Workspace,
+};
+
+/// Receivers and qualifiers that refer to the enclosing class. PHP and Perl
+/// keep the sigil, which is a word character there.
+const SELF_RECEIVERS: &[&str] = &["this", "self", "Self", "$this", "$self"];
+
+/// Kinds that open a scope other tags can live in.
+const SCOPE_KINDS: &[&str] = &[
+    "class",
+    "struct",
+    "union",
+    "namespace",
+    "interface",
+    "module",
+    "trait",
+    "enum",
+];
+
+const SCORE_UNRELATED: u32 = 0;
+const SCORE_NEUTRAL: u32 = 1;
+const SCORE_MATCH: u32 = 2;
+
+/// What the text around the cursor says about the scope of the symbol.
+#[derive(Debug, Default)]
+pub struct ScopeContext {
+    /// Explicit qualifier, e.g. `Foo` for `Foo::bar`.
+    pub qualifier: Option<String>,
+    /// Whether the symbol is accessed through `this->`, `self.` and the like.
+    pub self_receiver: bool,
+    /// Scopes enclosing the cursor, innermost first.
+    pub enclosing_scopes: Vec<String>,
+}
+
+impl ScopeContext {
+    pub fn at_position(
+        workspaces: &[Workspace],
+        document: &TextDocument,
+        uri: &Url,
+        position: Position,
+    ) -> io::Result<Self> {
+        let context = document.get_symbol_context(position)?;
+        let mut qualifier = (!context.qualifiers.is_empty()).then(|| context.qualifiers.join("::"));
+        let self_receiver = match (&qualifier, &context.receiver) {
+            (Some(qualifier), _) => SELF_RECEIVERS.contains(&qualifier.as_str()),
+            (None, Some(receiver)) => SELF_RECEIVERS.contains(&receiver.as_str()),
+            (None, None) => false,
+        };
+        if self_receiver {
+            qualifier = None;
+        }
+
+        let enclosing_scopes = match uri.to_file_path() {
+            Ok(path) => ScopeContext::enclosing_scopes(
+                &CtagsHandler::query_file_ctags(workspaces, &path)?,
+                position.line + 1,
+            ),
+            Err(_) => Vec::new(),
+        };
+
+        Ok(Self {
+            qualifier,
+            self_receiver,
+            enclosing_scopes,
+        })
+    }
+
+    /// Finds the scopes whose tags span `line`, using the `end:` field of the
+    /// current file's own tags. Functions contribute the scope they belong to.
+    fn enclosing_scopes(entries: &[CtagsEntry], line: u32) -> Vec<String> {
+        let mut enclosing: Vec<(u32, String)> = entries
+            .iter()
+            .filter(|entry| match (entry.line, entry.end) {
+                (Some(start), Some(end)) => start <= line && line <= end,
+                _ => false,
+            })
+            .filter_map(|entry| {
+                let scope = entry.scope.as_ref().map(|scope| scope.name.as_str());
+                let name = if SCOPE_KINDS.contains(&entry.kind_name()) {
+                    match scope {
+                        Some(scope) => format!("{}::{}", scope, entry.name),
+                        None => entry.name.clone(),
+                    }
+                } else {
+                    scope?.to_string()
+                };
+                Some((entry.line.unwrap_or_default(), name))
+            })
+            .collect();
+        enclosing.sort_by_key(|(start, _)| Reverse(*start));
+        enclosing.into_iter().map(|(_, name)| name).collect()
+    }
+
+    /// Whether the scope `name` of a tag refers to `target`, allowing either
+    /// side to be qualified further.
+    fn scope_matches(name: &str, target: &str) -> bool {
+        let normalize = |scope: &str| scope.replace('.', "::");
+        let (name, target) = (normalize(name), normalize(target));
+        name == target
+            || name.ends_with(&format!("::{}", target))
+            || target.ends_with(&format!("::{}", name))
+    }
+
+    fn score(&self, entry: &CtagsEntry) -> u32 {
+        let Some(scope) = &entry.scope else {
+            // A qualified or member access cannot name a global
+            return if self.qualifier.is_some() || self.self_receiver {
+                SCORE_UNRELATED
+            } else {
+                SCORE_NEUTRAL
+            };
+        };
+        if let Some(qualifier) = &self.qualifier {
+            return if ScopeContext::scope_matches(&scope.name, qualifier) {
+                SCORE_MATCH
+            } else {
+                SCORE_UNRELATED
+            };
+        }
+
+        let enclosing = self
+            .enclosing_scopes
+            .iter()
+            .any(|enclosing| ScopeContext::scope_matches(&scope.name, enclosing));
+        match (enclosing, self.self_receiver) {
+            (true, _) => SCORE_MATCH,
+            (false, true) => SCORE_UNRELATED,
+            (false, false) => SCORE_NEUTRAL,
+        }
+    }
+
+    /// Orders `entries` so that the ones whose scope matches the context come
+    /// first. When some entries match, entries from unrelated scopes are
+    /// dropped. The order is otherwise kept.
+    pub fn rank(&self, entries: Vec<CtagsEntry>) -> Vec<CtagsEntry> {
+        let mut scored: Vec<(u32, CtagsEntry)> = entries
+            .into_iter()
+            .map(|entry| (self.score(&entry), entry))
+            .collect();
+        if scored.iter().any(|(score, _)| *score == SCORE_MATCH) {
+            scored.retain(|(score, _)| *score != SCORE_UNRELATED);
+        }
+        scored.sort_by_key(|(score, _)| Reverse(*score));
+        scored.into_iter().map(|(_, entry)| entry).collect()
+    }
+}
@@ -1,31 +1,73 @@
 use std::{
+    collections::{HashMap, HashSet},
     io,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
-    },
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock, RwLock},
+    time::{Duration, SystemTime},
 };
 
-use lsp_server::{Connection, Message, Request, Response};
+use lsp_server::{Connection, Message, Request, RequestId, Response};
 use lsp_types::{
-    DidChangeTextDocumentParams, DidChangeWorkspaceFoldersParams, DidCloseTextDocumentParams,
-    DidOpenTextDocumentParams,
+    CancelParams, ClientCapabilities, DidChangeTextDocumentParams, DidChangeWatchedFilesParams,
+    DidChangeWorkspaceFoldersParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    DidSaveTextDocumentParams, NumberOrString, Url,
 };
+use notify::RecommendedWatcher;
 
 use crate::{
+    cancellation::CancellationToken,
+    code_action_handler::CodeActionHandler,
+    completion_handler::CompletionHandler,
     ctags::CtagsEntry,
-    document::{DocumentsCache, TextDocument},
+    document::{DocumentsCache, TextDocument, WordRules},
+    document_symbol_handler::DocumentSymbolHandler,
+    execute_command_handler::ExecuteCommandHandler,
+    file_watcher::{FileChanges, FileWatcher},
     goto_handler::GotoHandler,
+    hover_handler::HoverHandler,
     initialize_handler::InitializeHandler,
     logger::Logger,
-    workspace::WorkspaceManager,
+    lsp_error::LspError,
+    references_handler::ReferencesHandler,
+    tag_diagnostics::TagDiagnostics,
+    tag_generator::TagGenerator,
+    workspace::{WorkspaceConfig, WorkspaceManager},
+    workspace_symbol_handler::WorkspaceSymbolHandler,
 };
 
 pub struct LspServer {
     pub connection: Connection,
-    pub documents: Mutex<DocumentsCache>,
-    pub workspace_manager: Mutex<WorkspaceManager>,
-    shutdown_requested: Arc<AtomicBool>,
+    pub documents: Arc<RwLock<DocumentsCache>>,
+    pub workspace_manager: Arc<RwLock<WorkspaceManager>>,
+    pub client_capabilities: RwLock<ClientCapabilities>,
+    /// Watches the workspace folders when the client cannot do it for us.
+    pub watcher: Mutex<Option<RecommendedWatcher>>,
+    /// Cancellation tokens of the requests running on the blocking workers.
+    pending_requests: Mutex<HashMap<RequestId, Arc<CancellationToken>>>,
+    lifecycle: Mutex<Lifecycle>,
+    /// The `requestTimeout` option, kept out of the workspace manager so that
+    /// dispatching a request never waits for its lock.
+    request_timeout: OnceLock<Option<Duration>>,
+    /// Applies files changed outside the editor, once files are watched.
+    pub file_changes: OnceLock<FileChanges>,
+    /// Tags files already reported as out of date, with the time they were
+    /// written then.
+    pub stale_reported: Mutex<HashSet<(PathBuf, SystemTime)>>,
+    /// Requests sent to the client whose response someone waits for.
+    client_requests: Mutex<HashMap<RequestId, crossbeam_channel::Sender<Response>>>,
+}
+
+/// Where the server is in the LSP lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lifecycle {
+    /// Waiting for `initialize`. Other requests are rejected and
+    /// notifications dropped.
+    Uninitialized,
+    /// `initialize` was answered and the server is handling messages.
+    Running,
+    /// `shutdown` was answered. Only `exit` is left to handle.
+    ShutDown,
 }
 
 pub struct GotoDefinitionHandler;
@@ -53,93 +95,344 @@ impl LspServer {
     pub fn new(connection: Connection) -> Self {
         Self {
             connection,
-            documents: Mutex::new(DocumentsCache::new()),
-            workspace_manager: Mutex::new(WorkspaceManager::new(vec!["tags".to_string()])),
-            shutdown_requested: Arc::new(AtomicBool::new(false)),
+            documents: Arc::new(RwLock::new(DocumentsCache::new())),
+            workspace_manager: Arc::new(RwLock::new(WorkspaceManager::new(
+                WorkspaceConfig::default(),
+            ))),
+            client_capabilities: RwLock::new(ClientCapabilities::default()),
+            watcher: Mutex::new(None),
+            pending_requests: Mutex::new(HashMap::new()),
+            lifecycle: Mutex::new(Lifecycle::Uninitialized),
+            request_timeout: OnceLock::new(),
+            file_changes: OnceLock::new(),
+            stale_reported: Mutex::new(HashSet::new()),
+            client_requests: Mutex::new(HashMap::new()),
         }
     }
 
-    pub fn run(&self) -> io::Result<()> {
+    /// Handles messages until the `exit` notification or the end of the
+    /// connection, and returns the exit code of the process: 0 when the client
+    /// asked for a shutdown first, 1 otherwise.
+    pub fn run(self: &Arc<Self>) -> io::Result<i32> {
         for msg in &self.connection.receiver {
-            if self.shutdown_requested.load(Ordering::SeqCst) {
-                Logger::info("Shutdown requested, exiting...");
-                break;
-            }
             match msg {
-                Message::Request(req) => {
-                    if let Err(e) = self.handle_request(req) {
-                        Logger::error(&format!("Failed to handle request: {:?}", e));
-                    }
+                Message::Request(req) => self.dispatch(req),
+                Message::Response(resp) => self.handle_response(resp),
+                Message::Notification(notif) if notif.method == "exit" => {
+                    return Ok(self.exit_code());
                 }
-                Message::Response(_) => {}
                 Message::Notification(notif) => {
+                    if self.lifecycle() != Lifecycle::Running {
+                        Logger::info(&format!(
+                            "Dropping notification outside of the lifecycle: {:?}",
+                            notif.method
+                        ));
+                        continue;
+                    }
                     if let Err(e) = self.handle_notification(notif) {
                         Logger::error(&format!("Failed to handle notification: {:?}", e));
                     }
                 }
             }
         }
-        Ok(())
+        Logger::info("Connection closed before exit");
+        Ok(self.exit_code())
+    }
+
+    fn lifecycle(&self) -> Lifecycle {
+        *self.lifecycle.lock().unwrap()
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self.lifecycle() {
+            Lifecycle::ShutDown => 0,
+            Lifecycle::Uninitialized | Lifecycle::Running => 1,
+        }
+    }
+
+    /// Runs a request on a blocking worker so a slow lookup holds up neither
+    /// other requests nor the notifications after it, which are applied in
+    /// order on this thread. `initialize` and `shutdown` are handled in place
+    /// since everything after them depends on them. Every request gets exactly
+    /// one response: the handler sends the result, and failures are answered
+    /// here.
+    fn dispatch(self: &Arc<Self>, req: Request) {
+        let id = req.id.clone();
+        let rejection = match (self.lifecycle(), req.method.as_str()) {
+            (Lifecycle::Uninitialized, "initialize") | (Lifecycle::Running, "shutdown") => {
+                if let Err(e) = self.catch_panic(req) {
+                    self.respond_error(id, e);
+                }
+                return;
+            }
+            (Lifecycle::Uninitialized, _) => Some(LspError::ServerNotInitialized),
+            (Lifecycle::Running, "initialize") => Some(LspError::InvalidRequest(
+                "Server already initialized".to_string(),
+            )),
+            (Lifecycle::Running, _) => None,
+            (Lifecycle::ShutDown, _) => Some(LspError::InvalidRequest(
+                "Server is shutting down".to_string(),
+            )),
+        };
+        if let Some(e) = rejection {
+            self.respond_error(id, e);
+            return;
+        }
+
+        let timeout = self
+            .request_timeout
+            .get()
+            .copied()
+            .unwrap_or_else(|| WorkspaceConfig::default().request_timeout);
+        self.pending_requests
+            .lock()
+            .unwrap()
+            .insert(id.clone(), Arc::new(CancellationToken::new(timeout)));
+
+        let server = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            // A request cancelled while it was queued is not worth starting
+            let result = server
+                .cancellation_token(&id)
+                .check()
+                .map_err(LspError::from)
+                .and_then(|()| server.catch_panic(req));
+            server.pending_requests.lock().unwrap().remove(&id);
+            if let Err(e) = result {
+                server.respond_error(id, e);
+            }
+        });
+    }
+
+    /// Sends `request` to the client and returns where its response arrives.
+    /// The channel is closed without a response when the request could not
+    /// be sent.
+    pub fn request_client(&self, request: Request) -> crossbeam_channel::Receiver<Response> {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let id = request.id.clone();
+        self.client_requests
+            .lock()
+            .unwrap()
+            .insert(id.clone(), sender);
+        if let Err(e) = self.connection.sender.send(Message::Request(request)) {
+            Logger::error(&format!("Failed to send request {}: {:?}", id, e));
+            self.client_requests.lock().unwrap().remove(&id);
+        }
+        receiver
+    }
+
+    /// Hands a response of the client to whoever waits for it. Responses
+    /// nobody waits for are dropped.
+    fn handle_response(&self, resp: Response) {
+        let waiting = self.client_requests.lock().unwrap().remove(&resp.id);
+        match waiting {
+            Some(sender) => {
+                let _ = sender.send(resp);
+            }
+            None => Logger::info(&format!("Received response: {}", resp.id)),
+        }
+    }
+
+    pub fn set_request_timeout(&self, timeout: Option<Duration>) {
+        let _ = self.request_timeout.set(timeout);
+    }
+
+    /// Returns the cancellation token of the request `id`. Requests handled
+    /// in place get a token that is never cancelled.
+    pub fn cancellation_token(&self, id: &RequestId) -> Arc<CancellationToken> {
+        self.pending_requests
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(CancellationToken::none()))
     }
 
-    fn handle_request(&self, req: Request) -> io::Result<()> {
+    /// Returns a snapshot of the open document at `uri`.
+    pub fn document(&self, uri: &Url) -> Result<Arc<TextDocument>, LspError> {
+        self.documents.read().unwrap().get(uri).ok_or_else(|| {
+            Logger::error(&format!("Document not found: {:?}", uri));
+            LspError::RequestFailed(format!("Document not found: {}", uri))
+        })
+    }
+
+    /// Fails with [`LspError::ContentModified`] when the document at `uri` was
+    /// changed or closed since `document` was taken, which makes a result
+    /// computed from it stale.
+    pub fn ensure_current(&self, uri: &Url, document: &TextDocument) -> Result<(), LspError> {
+        match self.documents.read().unwrap().get(uri) {
+            Some(current) if current.version() == document.version() => Ok(()),
+            _ => Err(LspError::ContentModified),
+        }
+    }
+
+    fn respond_error(&self, id: RequestId, e: LspError) {
+        match e {
+            LspError::RequestCancelled | LspError::ContentModified => {
+                Logger::info(&format!("Request {} abandoned: {}", id, e))
+            }
+            _ => Logger::error(&format!("Request {} failed: {}", id, e)),
+        }
+        if let Err(e) = self
+            .connection
+            .sender
+            .send(Message::Response(e.to_response(id)))
+        {
+            Logger::error(&format!("Failed to send response: {:?}", e));
+        }
+    }
+
+    /// Handles `req`, turning a panic of its handler into an error so that the
+    /// request still gets a response.
+    fn catch_panic(&self, req: Request) -> Result<(), LspError> {
+        let method = req.method.clone();
+        panic::catch_unwind(AssertUnwindSafe(|| self.handle_request(req))).unwrap_or_else(
+            |payload| {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                Err(LspError::RequestFailed(format!(
+                    "{} panicked: {}",
+                    method, message
+                )))
+            },
+        )
+    }
+
+    fn handle_request(&self, req: Request) -> Result<(), LspError> {
         match req.method.as_str() {
-            "initialize" => InitializeHandler.handle(req, self),
+            "initialize" => {
+                InitializeHandler.handle(req, self)?;
+                *self.lifecycle.lock().unwrap() = Lifecycle::Running;
+                Ok(())
+            }
             "textDocument/definition" => GotoDefinitionHandler.handle(req, self),
             "textDocument/declaration" => GotoDeclarationHandler.handle(req, self),
             "textDocument/implementation" => GotoImplementationHandler.handle(req, self),
+            "textDocument/hover" => HoverHandler.handle(req, self),
+            "textDocument/references" => ReferencesHandler.handle(req, self),
+            "textDocument/completion" => CompletionHandler.handle(req, self),
+            "completionItem/resolve" => CompletionHandler.handle_resolve(req, self),
+            "textDocument/documentSymbol" => DocumentSymbolHandler.handle(req, self),
+            "workspace/symbol" => WorkspaceSymbolHandler.handle(req, self),
+            "textDocument/codeAction" => CodeActionHandler.handle(req, self),
+            "workspace/executeCommand" => ExecuteCommandHandler.handle(req, self),
             "shutdown" => {
-                self.shutdown_requested.store(true, Ordering::SeqCst);
+                *self.lifecycle.lock().unwrap() = Lifecycle::ShutDown;
+                // Nobody is waiting for the requests still running anymore
+                for token in self.pending_requests.lock().unwrap().values() {
+                    token.cancel();
+                }
                 let resp = Response::new_ok(req.id.clone(), ());
                 self.connection
                     .sender
                     .send(Message::Response(resp))
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                    .map_err(|e| io::Error::other(e.to_string()))?;
                 Ok(())
             }
             _ => {
                 Logger::info(&format!("Received unhandled request: {:?}", req.method));
-                Ok(())
+                Err(LspError::MethodNotFound(req.method))
             }
         }
     }
 
     fn handle_notification(&self, notif: lsp_server::Notification) -> io::Result<()> {
         match notif.method.as_str() {
+            "$/cancelRequest" => {
+                let params: CancelParams = serde_json::from_value(notif.params)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                let id = match params.id {
+                    NumberOrString::Number(id) => RequestId::from(id),
+                    NumberOrString::String(id) => RequestId::from(id),
+                };
+                // Requests that already finished have nothing left to cancel
+                if let Some(token) = self.pending_requests.lock().unwrap().get(&id) {
+                    token.cancel();
+                }
+            }
+            "initialized" => {
+                TagGenerator::generate_missing(self);
+                FileWatcher::start(self);
+            }
             "textDocument/didOpen" => {
                 let params: DidOpenTextDocumentParams = serde_json::from_value(notif.params)
                     .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
-                let mut documents = self.documents.lock().unwrap();
-                documents.insert(
-                    params.text_document.uri,
-                    TextDocument::new(params.text_document.text),
+                let (word_rules, encoding) = {
+                    let config = &self.workspace_manager.read().unwrap().config;
+                    (
+                        WordRules::for_language(
+                            &params.text_document.language_id,
+                            &config.word_characters,
+                        ),
+                        config.position_encoding,
+                    )
+                };
+                let uri = params.text_document.uri;
+                self.documents.write().unwrap().insert(
+                    uri.clone(),
+                    TextDocument::new(
+                        params.text_document.text,
+                        params.text_document.version,
+                        word_rules,
+                        encoding,
+                    ),
                 );
+                TagDiagnostics::new(self).publish(&uri);
             }
             "textDocument/didChange" => {
                 let params: DidChangeTextDocumentParams = serde_json::from_value(notif.params)
                     .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
-                let mut documents = self.documents.lock().unwrap();
+                let mut documents = self.documents.write().unwrap();
                 if let Some(doc) = documents.get_mut(&params.text_document.uri) {
-                    doc.apply_changes(params.content_changes);
+                    doc.apply_changes(params.content_changes, params.text_document.version);
+                }
+            }
+            "textDocument/didSave" => {
+                let params: DidSaveTextDocumentParams = serde_json::from_value(notif.params)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                if let Ok(path) = params.text_document.uri.to_file_path() {
+                    TagGenerator::retag(&self.workspace_manager, path, TagDiagnostics::new(self));
                 }
             }
             "textDocument/didClose" => {
                 let params: DidCloseTextDocumentParams = serde_json::from_value(notif.params)
                     .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
-                let mut documents = self.documents.lock().unwrap();
-                documents.remove(&params.text_document.uri);
+                self.documents
+                    .write()
+                    .unwrap()
+                    .remove(&params.text_document.uri);
+                TagDiagnostics::new(self).clear(&params.text_document.uri);
             }
             "workspace/didChangeWorkspaceFolders" => {
                 let params: DidChangeWorkspaceFoldersParams = serde_json::from_value(notif.params)?;
-                let mut manager = self.workspace_manager.lock().unwrap();
+                {
+                    let mut manager = self.workspace_manager.write().unwrap();
 
-                for folder in &params.event.removed {
-                    manager.remove_workspace(folder);
-                }
+                    for folder in &params.event.removed {
+                        manager.remove_workspace(folder);
+                    }
 
-                for folder in &params.event.added {
-                    manager.add_workspace(folder);
+                    for folder in &params.event.added {
+                        manager.add_workspace(folder);
+                    }
                 }
+                FileWatcher::update_folders(self, &params.event);
+                TagGenerator::generate_missing(self);
+                TagDiagnostics::new(self).refresh();
+            }
+            "workspace/didChangeWatchedFiles" => {
+                let params: DidChangeWatchedFilesParams = serde_json::from_value(notif.params)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                FileWatcher::changes(self).apply(
+                    params
+                        .changes
+                        .into_iter()
+                        .filter_map(|event| Some((event.uri.to_file_path().ok()?, event.typ)))
+                        .collect(),
+                );
             }
             _ => {
                 Logger::info(&format!(
@@ -0,0 +1,275 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use lsp_server::{Message, Notification};
+use lsp_types::{
+    Diagnostic, DiagnosticSeverity, NumberOrString, Position, PublishDiagnosticsParams, Range, Url,
+};
+
+use crate::{
+    document::DocumentsCache,
+    logger::Logger,
+    tag_generator::TagGenerator,
+    tag_index::TagIndex,
+    workspace::{Workspace, WorkspaceManager},
+    LspServer,
+};
+
+/// The `source` of the diagnostics published by the server.
+pub const DIAGNOSTIC_SOURCE: &str = "ctags_ls";
+
+/// Something wrong with the tags of the workspace containing a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagProblem {
+    /// No tags file matches the tags file patterns of the workspace.
+    MissingTags { folder: Url },
+    /// The tags file was written before the document was last saved. Only
+    /// generated tags files can be regenerated by the server.
+    StaleTags { tag_file: PathBuf, generated: bool },
+    /// The ctags command cannot be found to generate or refresh tags.
+    MissingCtags { folder: Url, command: String },
+}
+
+impl TagProblem {
+    /// The diagnostic code, which code actions use to recognize the problem.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TagProblem::MissingTags { .. } => "missing-tags",
+            TagProblem::StaleTags { .. } => "stale-tags",
+            TagProblem::MissingCtags { .. } => "missing-ctags",
+        }
+    }
+
+    /// The path of `folder`, which reads better than its URI.
+    pub fn display(folder: &Url) -> String {
+        folder
+            .to_file_path()
+            .map_or_else(|_| folder.to_string(), |path| path.display().to_string())
+    }
+
+    fn to_diagnostic(&self) -> Diagnostic {
+        let (severity, message) = match self {
+            TagProblem::MissingTags { folder } => (
+                DiagnosticSeverity::WARNING,
+                format!(
+                    "No tags file found for {}, so no tags can be looked up",
+                    TagProblem::display(folder)
+                ),
+            ),
+            TagProblem::StaleTags {
+                tag_file,
+                generated,
+            } => (
+                DiagnosticSeverity::INFORMATION,
+                format!(
+                    "The tags file {} is older than this file, so its tags may be out of date{}",
+                    tag_file.display(),
+                    if *generated {
+                        ""
+                    } else {
+                        "; regenerate it with the tool that built it"
+                    }
+                ),
+            ),
+            TagProblem::MissingCtags { command, .. } => (
+                DiagnosticSeverity::WARNING,
+                format!(
+                    "`{}` is not on PATH, so tags cannot be generated or refreshed; \
+                     install ctags or set `ctags.command`",
+                    command
+                ),
+            ),
+        };
+        Diagnostic {
+            range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+            severity: Some(severity),
+            code: Some(NumberOrString::String(self.code().to_string())),
+            source: Some(DIAGNOSTIC_SOURCE.to_string()),
+            message,
+            ..Diagnostic::default()
+        }
+    }
+}
+
+/// Publishes diagnostics about missing or outdated tags on open documents.
+///
+/// It only holds what it needs to publish, so background threads that change
+/// the tags can take a clone and refresh the diagnostics when they are done.
+#[derive(Clone)]
+pub struct TagDiagnostics {
+    sender: crossbeam_channel::Sender<Message>,
+    documents: Arc<RwLock<DocumentsCache>>,
+    workspace_manager: Arc<RwLock<WorkspaceManager>>,
+}
+
+impl TagDiagnostics {
+    pub fn new(server: &LspServer) -> Self {
+        Self {
+            sender: server.connection.sender.clone(),
+            documents: server.documents.clone(),
+            workspace_manager: server.workspace_manager.clone(),
+        }
+    }
+
+    /// Whether `command` names an existing file or is found in a directory
+    /// of `PATH`. On Windows, the extensions of `PATHEXT` are tried as well.
+    fn command_exists(command: &str) -> bool {
+        let candidates =
+            |path: PathBuf| {
+                let mut candidates = vec![path.clone()];
+                if cfg!(windows) && path.extension().is_none() {
+                    let extensions =
+                        env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".into());
+                    candidates.extend(extensions.split(';').filter(|ext| !ext.is_empty()).map(
+                        |ext| {
+                            let mut candidate = path.clone().into_os_string();
+                            candidate.push(ext);
+                            PathBuf::from(candidate)
+                        },
+                    ));
+                }
+                candidates
+            };
+        if Path::new(command).components().count() > 1 {
+            return candidates(PathBuf::from(command))
+                .iter()
+                .any(|path| path.is_file());
+        }
+        env::var_os("PATH").is_some_and(|paths| {
+            env::split_paths(&paths)
+                .flat_map(|dir| candidates(dir.join(command)))
+                .any(|path| path.is_file())
+        })
+    }
+
+    /// Returns the tags file of `workspace` that indexes `path` when it was
+    /// written before `path` was last modified. Files no tags file indexes,
+    /// such as a README, are never stale. Only tags files inside the workspace
+    /// folder or generated for it are considered, since shared tags files
+    /// elsewhere do not index its sources.
+    fn stale_tag_file(
+        manager: &WorkspaceManager,
+        workspace: &Workspace,
+        folder_path: &Path,
+        path: &Path,
+    ) -> Option<PathBuf> {
+        // Re-tagged files are current whatever the tags files say
+        if workspace.overlay.contains_key(&path.display().to_string()) {
+            return None;
+        }
+        let generated = TagGenerator::output_path(&manager.config.ctags, folder_path);
+        let (tag_file, written) = workspace
+            .tag_files
+            .iter()
+            .map(|tag_file| PathBuf::from(&tag_file.path))
+            .filter(|tag_file| {
+                tag_file.starts_with(folder_path) || generated.as_ref() == Some(tag_file)
+            })
+            .filter(|tag_file| TagIndex::load(tag_file).is_ok_and(|index| index.indexes_file(path)))
+            .filter_map(|tag_file| {
+                let written = fs::metadata(&tag_file).and_then(|m| m.modified()).ok()?;
+                Some((tag_file, written))
+            })
+            .max_by_key(|(_, written)| *written)?;
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+        (modified > written).then_some(tag_file)
+    }
+
+    /// Finds the problems with the tags of the workspace containing `path`.
+    pub fn problems(manager: &WorkspaceManager, path: &Path) -> Vec<TagProblem> {
+        let Some(workspace) = manager.workspace_for(path) else {
+            return Vec::new();
+        };
+        let Ok(folder_path) = workspace.folder.uri.to_file_path() else {
+            return Vec::new();
+        };
+        let folder = workspace.folder.uri.clone();
+
+        let mut problems = Vec::new();
+        if workspace.tag_files.is_empty() {
+            // Tags on their way need no warning, unless generating them fails
+            if !manager.generating.contains(&folder) {
+                problems.push(TagProblem::MissingTags {
+                    folder: folder.clone(),
+                });
+            }
+        } else if let Some(tag_file) =
+            TagDiagnostics::stale_tag_file(manager, workspace, &folder_path, path)
+        {
+            let generated =
+                TagGenerator::is_generated(&manager.config.ctags, &folder_path, &tag_file);
+            problems.push(TagProblem::StaleTags {
+                tag_file,
+                generated,
+            });
+        }
+        // Without ctags, existing tags still work unless they are missing or
+        // to be rewritten
+        let ctags = &manager.config.ctags;
+        let needs_ctags = workspace.tag_files.is_empty() || ctags.rewrite_on_save;
+        let command = &ctags.command;
+        if needs_ctags && !TagDiagnostics::command_exists(command) {
+            problems.push(TagProblem::MissingCtags {
+                folder,
+                command: command.clone(),
+            });
+        }
+        problems
+    }
+
+    fn send(&self, uri: Url, diagnostics: Vec<Diagnostic>, version: Option<i32>) {
+        let params = PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version,
+        };
+        if let Err(e) = self.sender.send(Message::Notification(Notification::new(
+            "textDocument/publishDiagnostics".to_string(),
+            params,
+        ))) {
+            Logger::error(&format!("Failed to publish diagnostics: {:?}", e));
+        }
+    }
+
+    /// Publishes the problems with the tags of the open document at `uri`.
+    pub fn publish(&self, uri: &Url) {
+        let Some(document) = self.documents.read().unwrap().get(uri) else {
+            return;
+        };
+        let Ok(path) = uri.to_file_path() else {
+            return;
+        };
+        let diagnostics = {
+            let manager = self.workspace_manager.read().unwrap();
+            TagDiagnostics::problems(&manager, &path)
+                .iter()
+                .map(TagProblem::to_diagnostic)
+                .collect()
+        };
+        self.send(uri.clone(), diagnostics, Some(document.version()));
+    }
+
+    /// Publishes the diagnostics of the open document of the file at `path`.
+    pub fn publish_path(&self, path: &Path) {
+        if let Ok(uri) = Url::from_file_path(path) {
+            self.publish(&uri);
+        }
+    }
+
+    /// Publishes the diagnostics of every open document again, after the
+    /// tags files or workspaces changed.
+    pub fn refresh(&self) {
+        let uris: Vec<Url> = self.documents.read().unwrap().uris().cloned().collect();
+        for uri in &uris {
+            self.publish(uri);
+        }
+    }
+
+    /// Clears the diagnostics of a document that was closed.
+    pub fn clear(&self, uri: &Url) {
+        self.send(uri.clone(), Vec::new(), None);
+    }
+}
@@ -0,0 +1,529 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use lsp_server::{Message, Notification, Request, RequestId};
+use lsp_types::{
+    MessageType, NumberOrString, ProgressParams, ProgressParamsValue, ShowMessageParams, Url,
+    WorkDoneProgress, WorkDoneProgressBegin, WorkDoneProgressCreateParams, WorkDoneProgressEnd,
+    WorkspaceFolder,
+};
+
+use crate::{
+    logger::Logger,
+    tag_diagnostics::TagDiagnostics,
+    tag_index::TagIndex,
+    workspace::{CtagsConfig, WorkspaceManager},
+    LspServer,
+};
+
+/// The raw tag lines of re-tagged files.
+type RetaggedFiles = HashMap<PathBuf, Vec<String>>;
+
+/// The `workspace/executeCommand` command that regenerates tags.
+pub const GENERATE_TAGS_COMMAND: &str = "ctags_ls.generateTags";
+/// The `workspace/executeCommand` command that regenerates tags files in place.
+pub const REFRESH_TAGS_COMMAND: &str = "ctags_ls.refreshTags";
+
+/// How long the client gets to accept a progress token before tags are
+/// generated without reporting progress.
+const PROGRESS_CREATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static NEXT_PROGRESS_ID: AtomicU64 = AtomicU64::new(0);
+
+pub struct TagGenerator;
+
+impl TagGenerator {
+    fn cache_root(config: &CtagsConfig) -> PathBuf {
+        if let Some(cache_dir) = &config.cache_dir {
+            return cache_dir.clone();
+        }
+        let base = env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .unwrap_or_else(env::temp_dir);
+        base.join("ctags_ls")
+    }
+
+    /// FNV-1a, so that a folder maps to the same cache entry across builds.
+    fn hash_path(path: &Path) -> u64 {
+        path.as_os_str()
+            .as_encoded_bytes()
+            .iter()
+            .fold(0xcbf29ce484222325, |hash, byte| {
+                (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+            })
+    }
+
+    /// Where the tags generated for `folder` are written.
+    pub fn output_path(config: &CtagsConfig, folder: &Path) -> Option<PathBuf> {
+        let name = folder.file_name()?.to_string_lossy();
+        Some(
+            TagGenerator::cache_root(config)
+                .join(format!("{}-{:016x}", name, TagGenerator::hash_path(folder)))
+                .join("tags"),
+        )
+    }
+
+    /// Whether `tag_file` is the tags file generated for `folder`, which the
+    /// server may regenerate. Other tags files are maintained by the user,
+    /// with options the server does not know.
+    pub fn is_generated(config: &CtagsConfig, folder: &Path, tag_file: &Path) -> bool {
+        TagGenerator::output_path(config, folder).is_some_and(|output| output == tag_file)
+    }
+
+    /// Runs ctags over `folder` and atomically replaces `output`. The folder is
+    /// passed as an absolute path so the tags carry absolute file names.
+    fn generate(config: &CtagsConfig, folder: &Path, output: &Path) -> io::Result<()> {
+        let dir = output
+            .parent()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid output path"))?;
+        fs::create_dir_all(dir)?;
+        let partial = output.with_extension("partial");
+
+        let result = Command::new(&config.command)
+            .args(&config.args)
+            .args(
+                config
+                    .exclude
+                    .iter()
+                    .map(|exclude| format!("--exclude={}", exclude)),
+            )
+            .arg("-f")
+            .arg(&partial)
+            .arg(folder)
+            .current_dir(folder)
+            .output()?;
+        if !result.status.success() {
+            let _ = fs::remove_file(&partial);
+            return Err(io::Error::other(format!(
+                "{} exited with {}: {}",
+                config.command,
+                result.status,
+                String::from_utf8_lossy(&result.stderr).trim()
+            )));
+        }
+        fs::rename(&partial, output)
+    }
+
+    fn send(sender: &crossbeam_channel::Sender<Message>, message: Message) {
+        if let Err(e) = sender.send(message) {
+            Logger::error(&format!("Failed to send message: {:?}", e));
+        }
+    }
+
+    fn report_progress(
+        sender: &crossbeam_channel::Sender<Message>,
+        token: &NumberOrString,
+        progress: WorkDoneProgress,
+    ) {
+        let params = ProgressParams {
+            token: token.clone(),
+            value: ProgressParamsValue::WorkDone(progress),
+        };
+        TagGenerator::send(
+            sender,
+            Message::Notification(Notification::new("$/progress".to_string(), params)),
+        );
+    }
+
+    /// Generates tags for `folder` on a background thread, reporting progress
+    /// when the client supports it. The generated file is added to the
+    /// workspace once ctags is done.
+    pub fn spawn(server: &LspServer, folder: WorkspaceFolder) {
+        TagGenerator::run(server, folder, None);
+    }
+
+    /// Regenerates the tags file `tag_file` of `folder` in place on a
+    /// background thread, like [`TagGenerator::spawn`].
+    pub fn refresh(server: &LspServer, folder: WorkspaceFolder, tag_file: PathBuf) {
+        TagGenerator::run(server, folder, Some(tag_file));
+    }
+
+    /// Runs ctags over `folder` into `tag_file`, or into the generated tags
+    /// file of the folder when it is `None`.
+    fn run(server: &LspServer, folder: WorkspaceFolder, tag_file: Option<PathBuf>) {
+        let Ok(folder_path) = folder.uri.to_file_path() else {
+            return;
+        };
+        let config = {
+            let mut manager = server.workspace_manager.write().unwrap();
+            if !manager.generating.insert(folder.uri.clone()) {
+                Logger::info(&format!(
+                    "Tags are already being generated for {}",
+                    folder.uri
+                ));
+                return;
+            }
+            manager.config.ctags.clone()
+        };
+        let in_place = tag_file.is_some();
+        let Some(output) = tag_file.or_else(|| TagGenerator::output_path(&config, &folder_path))
+        else {
+            server
+                .workspace_manager
+                .write()
+                .unwrap()
+                .generating
+                .remove(&folder.uri);
+            return;
+        };
+
+        let sender = server.connection.sender.clone();
+        let workspace_manager: Arc<RwLock<WorkspaceManager>> = server.workspace_manager.clone();
+        let diagnostics = TagDiagnostics::new(server);
+        let supports_progress = server
+            .client_capabilities
+            .read()
+            .unwrap()
+            .window
+            .as_ref()
+            .and_then(|window| window.work_done_progress)
+            .unwrap_or(false);
+        // Progress may only be reported once the client accepted the token
+        let progress = supports_progress.then(|| {
+            let id = NEXT_PROGRESS_ID.fetch_add(1, Ordering::SeqCst);
+            let token = NumberOrString::String(format!("ctags_ls/generate/{}", id));
+            let create = Request::new(
+                RequestId::from(format!("ctags_ls/progress/{}", id)),
+                "window/workDoneProgress/create".to_string(),
+                WorkDoneProgressCreateParams {
+                    token: token.clone(),
+                },
+            );
+            (token, server.request_client(create))
+        });
+
+        thread::spawn(move || {
+            let token = progress.and_then(|(token, created)| {
+                match created.recv_timeout(PROGRESS_CREATE_TIMEOUT) {
+                    Ok(resp) if resp.error.is_none() => Some(token),
+                    Ok(resp) => {
+                        Logger::info(&format!("Client refused progress token: {:?}", resp.error));
+                        None
+                    }
+                    Err(_) => None,
+                }
+            });
+            if let Some(token) = &token {
+                TagGenerator::report_progress(
+                    &sender,
+                    token,
+                    WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                        title: "Generating tags".to_string(),
+                        cancellable: Some(false),
+                        message: Some(folder.name.clone()),
+                        percentage: None,
+                    }),
+                );
+            }
+
+            Logger::info(&format!(
+                "Generating tags for {} into {:?}",
+                folder.uri, output
+            ));
+            let result = TagGenerator::generate(&config, &folder_path, &output);
+            let message = match &result {
+                Ok(()) => format!("Generated tags for {}", folder.name),
+                Err(e) => format!("Failed to generate tags for {}: {}", folder.name, e),
+            };
+
+            {
+                let mut manager = workspace_manager.write().unwrap();
+                manager.generating.remove(&folder.uri);
+                if result.is_ok() {
+                    if in_place {
+                        // Reloads the file and drops the overlay it supersedes
+                        manager.refresh_tag_files(std::slice::from_ref(&output));
+                    } else {
+                        manager.add_generated_tag_file(&folder.uri, &output);
+                    }
+                }
+            }
+
+            if let Some(token) = &token {
+                TagGenerator::report_progress(
+                    &sender,
+                    token,
+                    WorkDoneProgress::End(WorkDoneProgressEnd {
+                        message: Some(message.clone()),
+                    }),
+                );
+            }
+            match result {
+                Ok(()) => Logger::info(&message),
+                Err(_) => {
+                    Logger::error(&message);
+                    let params = ShowMessageParams {
+                        typ: MessageType::WARNING,
+                        message,
+                    };
+                    TagGenerator::send(
+                        &sender,
+                        Message::Notification(Notification::new(
+                            "window/showMessage".to_string(),
+                            params,
+                        )),
+                    );
+                }
+            }
+            diagnostics.refresh();
+        });
+    }
+
+    /// Generates tags for every workspace that has no tags file, unless
+    /// automatic generation is turned off.
+    pub fn generate_missing(server: &LspServer) {
+        let folders: Vec<WorkspaceFolder> = {
+            let manager = server.workspace_manager.read().unwrap();
+            if !manager.config.ctags.auto_generate {
+                return;
+            }
+            manager
+                .workspaces
+                .iter()
+                .filter(|workspace| workspace.tag_files.is_empty())
+                .map(|workspace| workspace.folder.clone())
+                .collect()
+        };
+        for folder in folders {
+            Logger::info(&format!("No tags file found for {}", folder.uri));
+            TagGenerator::spawn(server, folder);
+        }
+    }
+
+    /// Runs ctags once on the files `paths` and returns the raw tag lines of
+    /// each of them, which are empty for files without tags.
+    fn tag_files(config: &CtagsConfig, paths: &[PathBuf]) -> io::Result<RetaggedFiles> {
+        let result = Command::new(&config.command)
+            .args(
+                config
+                    .args
+                    .iter()
+                    .filter(|arg| !arg.starts_with("--recurse") && *arg != "-R"),
+            )
+            .arg("-f")
+            .arg("-")
+            .args(paths)
+            .output()?;
+        if !result.status.success() {
+            return Err(io::Error::other(format!(
+                "{} exited with {}: {}",
+                config.command,
+                result.status,
+                String::from_utf8_lossy(&result.stderr).trim()
+            )));
+        }
+        let mut tagged: RetaggedFiles = paths
+            .iter()
+            .map(|path| (path.clone(), Vec::new()))
+            .collect();
+        // The files are named as they were passed to ctags
+        for line in String::from_utf8_lossy(&result.stdout)
+            .lines()
+            .filter(|line| !line.starts_with("!_"))
+        {
+            if let Some(lines) = line
+                .split('\t')
+                .nth(1)
+                .and_then(|file| tagged.get_mut(Path::new(file)))
+            {
+                lines.push(line.to_string());
+            }
+        }
+        Ok(tagged)
+    }
+
+    /// Re-tags `path` after it was saved, on a background thread, like
+    /// [`TagGenerator::retag_files`].
+    pub fn retag(
+        workspace_manager: &Arc<RwLock<WorkspaceManager>>,
+        path: PathBuf,
+        diagnostics: TagDiagnostics,
+    ) {
+        let workspace_manager = workspace_manager.clone();
+        thread::spawn(move || {
+            TagGenerator::retag_files(&workspace_manager, &[path], &diagnostics);
+        });
+    }
+
+    /// Re-tags `paths` with a single ctags run. The new tags go to the overlay
+    /// of the workspace containing each file, and optionally into its tags
+    /// files. The diagnostics of the files are published again once it is
+    /// done.
+    pub fn retag_files(
+        workspace_manager: &Arc<RwLock<WorkspaceManager>>,
+        paths: &[PathBuf],
+        diagnostics: &TagDiagnostics,
+    ) {
+        let (config, matcher) = {
+            let manager = workspace_manager.read().unwrap();
+            (manager.config.ctags.clone(), manager.path_matcher())
+        };
+        // Excluded files would not be in tags generated for the workspace
+        let paths: Vec<PathBuf> = paths
+            .iter()
+            .filter(|path| matcher.contains(path) && !matcher.is_excluded(path))
+            .cloned()
+            .collect();
+        if paths.is_empty() {
+            return;
+        }
+
+        match TagGenerator::tag_files(&config, &paths) {
+            Ok(tagged) => TagGenerator::apply_retagged(workspace_manager, &config, tagged),
+            Err(e) => Logger::error(&format!("Failed to re-tag {:?}: {}", paths, e)),
+        }
+        for path in &paths {
+            diagnostics.publish_path(path);
+        }
+    }
+
+    fn apply_retagged(
+        workspace_manager: &Arc<RwLock<WorkspaceManager>>,
+        config: &CtagsConfig,
+        tagged: RetaggedFiles,
+    ) {
+        // Re-tagged files by workspace, with the tags files to rewrite
+        let mut by_workspace: HashMap<Url, (Vec<PathBuf>, RetaggedFiles)> = HashMap::new();
+        {
+            let mut manager = workspace_manager.write().unwrap();
+            for (path, lines) in tagged {
+                let Some(workspace) = manager.workspace_for_mut(&path) else {
+                    continue;
+                };
+                let file = path.display().to_string();
+                Logger::info(&format!("Re-tagged {} with {} tags", file, lines.len()));
+                workspace.overlay.insert(file, lines.clone());
+                by_workspace
+                    .entry(workspace.folder.uri.clone())
+                    .or_insert_with(|| {
+                        let tag_files = workspace
+                            .tag_files
+                            .iter()
+                            .map(|tag_file| PathBuf::from(&tag_file.path))
+                            .collect();
+                        (tag_files, HashMap::new())
+                    })
+                    .1
+                    .insert(path, lines);
+            }
+        }
+        if !config.rewrite_on_save {
+            return;
+        }
+
+        for (folder, (tag_files, retagged)) in by_workspace {
+            match TagGenerator::rewrite_tag_files(&tag_files, &retagged) {
+                Ok(()) => {
+                    // The tags files are current again, except for files that
+                    // were re-tagged once more in the meantime
+                    let mut manager = workspace_manager.write().unwrap();
+                    if let Some(workspace) = manager
+                        .workspaces_mut()
+                        .iter_mut()
+                        .find(|w| w.folder.uri == folder)
+                    {
+                        for (path, lines) in &retagged {
+                            let file = path.display().to_string();
+                            if workspace.overlay.get(&file) == Some(lines) {
+                                workspace.overlay.remove(&file);
+                            }
+                        }
+                    }
+                }
+                Err(e) => Logger::error(&format!("Failed to rewrite tags files: {}", e)),
+            }
+        }
+    }
+
+    /// The non-empty lines of a tags file, without their line terminators.
+    fn tag_lines(content: &[u8]) -> impl Iterator<Item = &[u8]> {
+        content
+            .split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .filter(|line| !line.is_empty())
+    }
+
+    /// Replaces the tags of the files in `retagged` in the tags files that
+    /// mention them. The tags of a file no tags file mentions go to the first
+    /// tags file. Files are written back sorted.
+    fn rewrite_tag_files(tag_files: &[PathBuf], retagged: &RetaggedFiles) -> io::Result<()> {
+        // Relative file names are resolved the way lookups resolve them
+        let retagged_file = |line: &[u8], base_dir: &Path| {
+            let file = line.split(|&b| b == b'\t').nth(1)?;
+            let file = PathBuf::from(String::from_utf8_lossy(file).as_ref());
+            let path = if file.is_absolute() {
+                file
+            } else {
+                base_dir.join(file)
+            };
+            retagged.get_key_value(&path).map(|(path, _)| path.clone())
+        };
+
+        // Tags files are worked on as bytes, since ctags copies the bytes of
+        // the sources into the patterns whatever their encoding
+        let mut contents = Vec::new();
+        let mut mentioned: Vec<HashSet<PathBuf>> = Vec::new();
+        for tag_file in tag_files {
+            let content = fs::read(tag_file)?;
+            let lines: Vec<&[u8]> = TagGenerator::tag_lines(&content).collect();
+            let proc_cwd = lines
+                .iter()
+                .take_while(|line| line.starts_with(b"!_"))
+                .find_map(|line| line.strip_prefix(b"!_TAG_PROC_CWD\t"))
+                .and_then(|rest| rest.split(|&b| b == b'\t').next())
+                .map(String::from_utf8_lossy);
+            let base_dir = TagIndex::resolve_base_dir(tag_file, proc_cwd.as_deref());
+            mentioned.push(
+                lines
+                    .iter()
+                    .filter_map(|line| retagged_file(line, &base_dir))
+                    .collect(),
+            );
+            contents.push((content, base_dir));
+        }
+
+        for (idx, (tag_file, (content, base_dir))) in tag_files.iter().zip(&contents).enumerate() {
+            let additions: Vec<&[u8]> = retagged
+                .iter()
+                .filter(|(path, _)| {
+                    mentioned[idx].contains(*path)
+                        || (idx == 0 && !mentioned.iter().any(|files| files.contains(*path)))
+                })
+                .flat_map(|(_, lines)| lines.iter().map(String::as_bytes))
+                .collect();
+            if mentioned[idx].is_empty() && additions.is_empty() {
+                continue;
+            }
+            let mut rewritten: Vec<&[u8]> = TagGenerator::tag_lines(content)
+                .filter(|line| {
+                    retagged_file(line, base_dir).is_none()
+                        && !line.starts_with(b"!_TAG_FILE_SORTED\t")
+                })
+                .chain(additions)
+                .chain(std::iter::once(
+                    b"!_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted, 2=foldcase/".as_slice(),
+                ))
+                .collect();
+            rewritten.sort_unstable();
+
+            let partial = tag_file.with_extension("partial");
+            let mut text = rewritten.join(&b'\n');
+            text.push(b'\n');
+            fs::write(&partial, text)?;
+            fs::rename(&partial, tag_file)?;
+            Logger::info(&format!("Rewrote tags file {:?}", tag_file));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+    time::SystemTime,
+};
+
+use crate::{
+    ctags::CtagsHandler,
+    logger::Logger,
+    tags_reader::{SortOrder, TagsReader},
+};
+
+/// Loaded tags files by path, shared by every workspace that uses them.
+static INDEXES: OnceLock<Mutex<HashMap<PathBuf, Arc<TagIndex>>>> = OnceLock::new();
+
+/// Index over a memory-mapped tags file.
+///
+/// Name lookups in sorted files search the mapping in place. The secondary
+/// indexes only hold line offsets and are built the first time a query needs
+/// them, so a large tags file costs little memory until it is queried by file
+/// or scope.
+pub struct TagIndex {
+    reader: TagsReader,
+    /// Where relative file names in the tags file are resolved from.
+    base_dir: PathBuf,
+    modified: Option<SystemTime>,
+    len: u64,
+    /// Tag lines ordered by name, for files that are not sorted already.
+    by_name: OnceLock<Vec<usize>>,
+    /// Tag lines by the file column as written in the tags file.
+    by_file: OnceLock<HashMap<String, Vec<usize>>>,
+    /// Tag lines by the name of their scope.
+    by_scope: OnceLock<HashMap<String, Vec<usize>>>,
+}
+
+impl TagIndex {
+    /// Returns the index of the tags file at `path`, mapping the file again
+    /// when it changed on disk since it was last mapped. The file is mapped
+    /// outside the lock, so loading one tags file does not hold up lookups in
+    /// the others.
+    pub fn load(path: &Path) -> io::Result<Arc<TagIndex>> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified().ok();
+        let is_current =
+            |index: &TagIndex| index.modified == modified && index.len == metadata.len();
+        let indexes = INDEXES.get_or_init(Default::default);
+        if let Some(index) = indexes.lock().unwrap().get(path)
+            && is_current(index)
+        {
+            return Ok(index.clone());
+        }
+
+        Logger::info(&format!("Loading tags file {:?}", path));
+        let reader = TagsReader::open(path)?;
+        let base_dir = TagIndex::resolve_base_dir(path, reader.pseudo_tag("TAG_PROC_CWD"));
+        let index = Arc::new(TagIndex {
+            reader,
+            base_dir,
+            modified,
+            len: metadata.len(),
+            by_name: OnceLock::new(),
+            by_file: OnceLock::new(),
+            by_scope: OnceLock::new(),
+        });
+
+        // Another lookup may have loaded the same file meanwhile
+        let mut indexes = indexes.lock().unwrap();
+        if let Some(loaded) = indexes.get(path)
+            && is_current(loaded)
+        {
+            return Ok(loaded.clone());
+        }
+        indexes.insert(path.to_path_buf(), index.clone());
+        Ok(index)
+    }
+
+    /// Relative file names are relative to the directory ctags ran in, which
+    /// `!_TAG_PROC_CWD` records, or else to the directory of the tags file,
+    /// as vim resolves them.
+    pub fn resolve_base_dir(path: &Path, proc_cwd: Option<&str>) -> PathBuf {
+        proc_cwd
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| path.parent().map(Path::to_path_buf))
+            .unwrap_or_default()
+    }
+
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    /// Drops the index of the tags file at `path`, releasing its mapping.
+    pub fn invalidate(path: &Path) {
+        if let Some(indexes) = INDEXES.get() {
+            indexes.lock().unwrap().remove(path);
+        }
+    }
+
+    fn name_at(&self, offset: usize) -> &[u8] {
+        TagsReader::tag_name(self.reader.line_at(offset))
+    }
+
+    fn sorted_offsets(&self) -> Vec<usize> {
+        let mut offsets: Vec<usize> = self.reader.lines().map(|(offset, _)| offset).collect();
+        offsets
+            .sort_by(|a, b| TagsReader::compare_names(self.name_at(*a), self.name_at(*b), false));
+        offsets
+    }
+
+    /// Collects up to `limit` lines from `offsets`, which are ordered by name,
+    /// whose name matches `key`.
+    fn find_sorted(&self, offsets: &[usize], key: &str, prefix: bool, limit: usize) -> Vec<String> {
+        let key = key.as_bytes();
+        let start = offsets.partition_point(|offset| {
+            TagsReader::compare_names(self.name_at(*offset), key, false).is_lt()
+        });
+        offsets[start..]
+            .iter()
+            .map(|offset| self.reader.line_at(*offset))
+            .take_while(|line| TagsReader::is_match(TagsReader::tag_name(line), key, prefix, false))
+            .take(limit)
+            .map(TagsReader::decode_line)
+            .collect()
+    }
+
+    /// Returns the raw tag lines whose name is exactly `name`.
+    pub fn find(&self, name: &str) -> Vec<String> {
+        match self.reader.sort_order() {
+            SortOrder::Sorted | SortOrder::FoldCase => self.reader.find(name),
+            SortOrder::Unsorted => {
+                let by_name = self.by_name.get_or_init(|| self.sorted_offsets());
+                self.find_sorted(by_name, name, false, usize::MAX)
+            }
+        }
+    }
+
+    /// Returns up to `limit` raw tag lines whose name starts with `prefix`.
+    pub fn find_prefix(&self, prefix: &str, limit: usize) -> Vec<String> {
+        match self.reader.sort_order() {
+            SortOrder::Sorted | SortOrder::FoldCase => self.reader.find_prefix(prefix, limit),
+            SortOrder::Unsorted => {
+                let by_name = self.by_name.get_or_init(|| self.sorted_offsets());
+                self.find_sorted(by_name, prefix, true, limit)
+            }
+        }
+    }
+
+    /// Returns up to `limit` raw tag lines whose name starts with `prefix`,
+    /// ignoring ASCII case. Files not sorted with case folding are searched
+    /// once for every case variant of `prefix` that some name starts with.
+    pub fn find_prefix_ignore_case(&self, prefix: &str, limit: usize) -> Vec<String> {
+        match self.reader.sort_order() {
+            SortOrder::FoldCase => self.reader.find_prefix_ignore_case(prefix, limit),
+            SortOrder::Sorted | SortOrder::Unsorted => {
+                let mut lines = Vec::new();
+                self.find_case_variants(&mut String::new(), prefix, limit, &mut lines);
+                lines
+            }
+        }
+    }
+
+    /// Extends `head` with both cases of each letter of `rest`, dropping the
+    /// variants no name starts with, and collects the lines of the complete
+    /// ones.
+    fn find_case_variants(
+        &self,
+        head: &mut String,
+        rest: &str,
+        limit: usize,
+        lines: &mut Vec<String>,
+    ) {
+        if lines.len() >= limit {
+            return;
+        }
+        let Some(c) = rest.chars().next() else {
+            lines.extend(self.find_prefix(head, limit - lines.len()));
+            return;
+        };
+        let variants = if c.is_ascii_alphabetic() {
+            vec![c.to_ascii_uppercase(), c.to_ascii_lowercase()]
+        } else {
+            vec![c]
+        };
+        for variant in variants {
+            head.push(variant);
+            if !self.find_prefix(head, 1).is_empty() {
+                self.find_case_variants(head, &rest[c.len_utf8()..], limit, lines);
+            }
+            head.pop();
+        }
+    }
+
+    fn group_by(&self, key: impl Fn(&str) -> Option<String>) -> HashMap<String, Vec<usize>> {
+        let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+        for (offset, line) in self.reader.lines() {
+            if let Some(key) = key(&String::from_utf8_lossy(line)) {
+                groups.entry(key).or_default().push(offset);
+            }
+        }
+        groups
+    }
+
+    fn by_file(&self) -> &HashMap<String, Vec<usize>> {
+        self.by_file
+            .get_or_init(|| self.group_by(|line| line.split('\t').nth(1).map(str::to_string)))
+    }
+
+    fn by_scope(&self) -> &HashMap<String, Vec<usize>> {
+        self.by_scope
+            .get_or_init(|| self.group_by(CtagsHandler::tag_scope))
+    }
+
+    fn decode_lines(&self, offsets: &[usize]) -> Vec<String> {
+        offsets
+            .iter()
+            .map(|offset| TagsReader::decode_line(self.reader.line_at(*offset)))
+            .collect()
+    }
+
+    /// Returns the raw tag lines whose file column is exactly `file`, in file
+    /// order.
+    pub fn find_file(&self, file: &str) -> Vec<String> {
+        self.by_file()
+            .get(file)
+            .map_or_else(Vec::new, |offsets| self.decode_lines(offsets))
+    }
+
+    /// Returns the file columns `path` may be written as: absolute, or
+    /// relative to the base directory of the tags file.
+    pub fn file_columns(&self, path: &Path) -> Vec<String> {
+        let mut columns = vec![path.display().to_string()];
+        if let Ok(relative) = path.strip_prefix(&self.base_dir) {
+            columns.push(relative.display().to_string());
+            columns.push(format!("./{}", relative.display()));
+        }
+        columns
+    }
+
+    /// Whether the tags file has tags for the file at `path`.
+    pub fn indexes_file(&self, path: &Path) -> bool {
+        self.file_columns(path)
+            .iter()
+            .any(|column| self.by_file().contains_key(column))
+    }
+
+    /// Returns the file column of every tag, once per file.
+    pub fn files(&self) -> Vec<&str> {
+        self.by_file().keys().map(String::as_str).collect()
+    }
+
+    /// Returns the raw tag lines whose scope is `scope`, or a scope ending in
+    /// `scope` such as `ns::Foo` for `Foo`.
+    pub fn find_scope(&self, scope: &str) -> Vec<String> {
+        let mut offsets: Vec<usize> = self
+            .by_scope()
+            .iter()
+            .filter(|(name, _)| CtagsHandler::is_in_scope(name, scope))
+            .flat_map(|(_, offsets)| offsets.iter().copied())
+            .collect();
+        offsets.sort_unstable();
+        self.decode_lines(&offsets)
+    }
+
+    /// Calls `visit` with every tag line in file order, stopping at the first
+    /// error it returns.
+    pub fn try_for_each<E>(&self, visit: impl FnMut(&str) -> Result<(), E>) -> Result<(), E> {
+        self.reader.try_for_each(visit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, sorted: &str, names: &[&str]) -> Arc<TagIndex> {
+        let path = std::env::temp_dir().join(format!("ctags_ls-{}-{}", std::process::id(), name));
+        let mut data = format!("!_TAG_FILE_SORTED\t{sorted}\t/0=unsorted/\n");
+        for name in names {
+            data.push_str(&format!("{name}\tf.c\t/^{name}$/;\"\tv\n"));
+        }
+        fs::write(&path, data).unwrap();
+        let index = TagIndex::load(&path).unwrap();
+        // Mapped files cannot be removed on every platform
+        let _ = fs::remove_file(&path);
+        index
+    }
+
+    fn names(lines: &[String]) -> Vec<&str> {
+        let mut names: Vec<&str> = lines
+            .iter()
+            .map(|line| line.split('\t').next().unwrap())
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    const NAMES: &[&str] = &["RENDER", "Render", "reNder", "render_all", "rent", "x_1"];
+
+    #[test]
+    fn sorted_find_prefix_ignore_case() {
+        let mut sorted = NAMES.to_vec();
+        sorted.sort_unstable();
+        let index = load("sorted", "1", &sorted);
+        assert_eq!(
+            names(&index.find_prefix_ignore_case("rend", usize::MAX)),
+            ["RENDER", "Render", "reNder", "render_all"]
+        );
+        assert_eq!(
+            names(&index.find_prefix_ignore_case("X_", usize::MAX)),
+            ["x_1"]
+        );
+        assert_eq!(index.find_prefix_ignore_case("rend", 2).len(), 2);
+        assert!(index
+            .find_prefix_ignore_case("rendering", usize::MAX)
+            .is_empty());
+    }
+
+    #[test]
+    fn unsorted_find_prefix_ignore_case() {
+        let index = load(
+            "unsorted",
+            "0",
+            &["rent", "x_1", "reNder", "RENDER", "render_all", "Render"],
+        );
+        assert_eq!(
+            names(&index.find_prefix_ignore_case("REN", usize::MAX)),
+            ["RENDER", "Render", "reNder", "render_all", "rent"]
+        );
+        assert_eq!(
+            names(&index.find_prefix("ren", usize::MAX)),
+            ["render_all", "rent"]
+        );
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::HashSet;
+
+use crate::ctags::{CtagsEntry, TagPattern};
+
+/// Lines around the `line:` hint of a tag searched before the whole file.
+const NEARBY_WINDOW: usize = 100;
+/// Share of the pattern's words a line needs to be taken for a moved tag.
+const MIN_SIMILARITY: f64 = 0.5;
+/// Words that introduce a definition in common languages.
+const DEFINITION_KEYWORDS: &[&str] = &[
+    "class",
+    "struct",
+    "union",
+    "enum",
+    "interface",
+    "trait",
+    "impl",
+    "namespace",
+    "module",
+    "package",
+    "type",
+    "typedef",
+    "define",
+    "def",
+    "fn",
+    "func",
+    "function",
+    "sub",
+    "let",
+    "const",
+    "var",
+    "static",
+];
+
+/// How the location of a tag was found, from the most to the least exact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocateStrategy {
+    /// The line given by the `line:` field still matches the pattern.
+    Line,
+    /// The pattern matches a line near the `line:` field.
+    Nearby,
+    /// The pattern matches a line somewhere in the file.
+    Pattern,
+    /// The pattern matches a line once runs of whitespace are ignored.
+    Normalized,
+    /// A line defining the name looks enough like the pattern.
+    Similar,
+}
+
+impl LocateStrategy {
+    pub fn description(self) -> &'static str {
+        match self {
+            LocateStrategy::Line => "line number",
+            LocateStrategy::Nearby => "search near the line number",
+            LocateStrategy::Pattern => "pattern search",
+            LocateStrategy::Normalized => "whitespace-insensitive pattern search",
+            LocateStrategy::Similar => "similarity search",
+        }
+    }
+}
+
+/// Where a tag was found in the lines of its file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagMatch {
+    pub line: usize,
+    /// Byte offset of the name of the tag in the line.
+    pub column: usize,
+    pub strategy: LocateStrategy,
+    /// Whether the tags file no longer describes the line: the tag moved away
+    /// from its `line:` field or its pattern no longer matches as written.
+    pub stale: bool,
+}
+
+pub struct TagLocator;
+
+impl TagLocator {
+    /// Finds `entry` in `lines`, the contents of its file. The `line:` field is
+    /// tried first, then the pattern near that line and in the whole file.
+    /// When the pattern is gone because the file was edited since tagging, a
+    /// whitespace-insensitive match and finally the definition of the name
+    /// that looks most like the pattern are taken instead. Only lines that
+    /// still contain the name are taken.
+    pub fn locate(entry: &CtagsEntry, lines: &[String]) -> Option<TagMatch> {
+        let hint = entry
+            .line
+            .and_then(|line| line.checked_sub(1))
+            .map(|line| line as usize);
+        let column = |line: usize| TagLocator::name_column(&lines[line], &entry.name);
+        let found = |line: usize, strategy: LocateStrategy| {
+            let stale = match strategy {
+                LocateStrategy::Line => false,
+                LocateStrategy::Pattern => hint.is_some(),
+                _ => true,
+            };
+            Some(TagMatch {
+                line,
+                column: column(line)?,
+                strategy,
+                stale,
+            })
+        };
+
+        let Some(pattern) = &entry.pattern else {
+            // Without a pattern there is nothing to check the line against
+            return hint
+                .filter(|line| *line < lines.len())
+                .and_then(|line| found(line, LocateStrategy::Line));
+        };
+        let matches = |line: usize| pattern.matches(&lines[line]) && column(line).is_some();
+
+        if let Some(hint) = hint.filter(|hint| *hint < lines.len()) {
+            if matches(hint) {
+                return found(hint, LocateStrategy::Line);
+            }
+            let start = hint.saturating_sub(NEARBY_WINDOW);
+            let end = (hint + NEARBY_WINDOW + 1).min(lines.len());
+            if let Some(line) = TagLocator::nearest(start..end, hint, matches) {
+                return found(line, LocateStrategy::Nearby);
+            }
+        }
+
+        let hint_or_start = hint.unwrap_or(0);
+        if let Some(line) = TagLocator::nearest(0..lines.len(), hint_or_start, matches) {
+            return found(line, LocateStrategy::Pattern);
+        }
+
+        let normalized = TagLocator::normalize(&pattern.text);
+        if !normalized.is_empty()
+            && let Some(line) = TagLocator::nearest(0..lines.len(), hint_or_start, |line| {
+                TagLocator::matches_normalized(pattern, &normalized, &lines[line])
+                    && column(line).is_some()
+            })
+        {
+            return found(line, LocateStrategy::Normalized);
+        }
+
+        TagLocator::most_similar(entry, pattern, lines, hint_or_start)
+            .and_then(|line| found(line, LocateStrategy::Similar))
+    }
+
+    /// Returns the line in `range` closest to `origin` that satisfies
+    /// `is_match`, preferring the earlier one of two at the same distance.
+    fn nearest(
+        range: std::ops::Range<usize>,
+        origin: usize,
+        is_match: impl Fn(usize) -> bool,
+    ) -> Option<usize> {
+        range
+            .filter(|line| is_match(*line))
+            .min_by_key(|line| line.abs_diff(origin))
+    }
+
+    /// Collapses every run of whitespace to a single space and trims the ends.
+    fn normalize(text: &str) -> String {
+        text.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    fn matches_normalized(pattern: &TagPattern, normalized: &str, line: &str) -> bool {
+        let line = TagLocator::normalize(line);
+        match (pattern.anchored_start, pattern.anchored_end) {
+            (true, true) => line == normalized,
+            (true, false) => line.starts_with(normalized),
+            (false, true) => line.ends_with(normalized),
+            (false, false) => line.contains(normalized),
+        }
+    }
+
+    fn words(text: &str) -> impl Iterator<Item = &str> {
+        text.split(|c: char| !c.is_alphanumeric() && c != '_')
+            .filter(|word| !word.is_empty())
+    }
+
+    /// Whether `name` appears on `line` in a way that looks like it is being
+    /// defined rather than used: after a definition keyword, or followed by
+    /// the start of a body, parameter list, value or type.
+    fn is_definition_like(line: &str, name: &str) -> bool {
+        let code = line.trim_start();
+        if ["//", "/*", "*", "#"]
+            .iter()
+            .any(|comment| code.starts_with(comment))
+            && !code.starts_with("#define")
+        {
+            return false;
+        }
+        let Some(column) = TagLocator::find_word(line, name) else {
+            return false;
+        };
+        let before = &line[..column];
+        let after = line[column + name.len()..].trim_start();
+        TagLocator::words(before)
+            .last()
+            .is_some_and(|word| DEFINITION_KEYWORDS.contains(&word))
+            || after.is_empty()
+            || after.starts_with(['(', '{', '=', ';', ':', '<', '['])
+    }
+
+    /// Among the lines defining the name of `entry`, returns the one sharing
+    /// the most words with the pattern, if it shares enough of them. Ties go
+    /// to the line closest to `origin`.
+    fn most_similar(
+        entry: &CtagsEntry,
+        pattern: &TagPattern,
+        lines: &[String],
+        origin: usize,
+    ) -> Option<usize> {
+        let pattern_words: HashSet<&str> = TagLocator::words(&pattern.text).collect();
+        if pattern_words.is_empty() {
+            return None;
+        }
+        lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| TagLocator::is_definition_like(line, &entry.name))
+            .map(|(line_num, line)| {
+                let line_words: HashSet<&str> = TagLocator::words(line).collect();
+                let shared = pattern_words.intersection(&line_words).count();
+                let similarity = shared as f64 / pattern_words.union(&line_words).count() as f64;
+                (line_num, similarity)
+            })
+            .filter(|(_, similarity)| *similarity >= MIN_SIMILARITY)
+            .max_by(|(a_line, a), (b_line, b)| {
+                a.total_cmp(b)
+                    .then_with(|| b_line.abs_diff(origin).cmp(&a_line.abs_diff(origin)))
+            })
+            .map(|(line_num, _)| line_num)
+    }
+
+    /// Returns the byte offset of `name` as a whole word in `line`.
+    fn find_word(line: &str, name: &str) -> Option<usize> {
+        let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+        line.match_indices(name).map(|(idx, _)| idx).find(|&idx| {
+            !line[..idx].chars().next_back().is_some_and(is_word_char)
+                && !line[idx + name.len()..]
+                    .chars()
+                    .next()
+                    .is_some_and(is_word_char)
+        })
+    }
+
+    /// Returns the byte offset of the name of a tag in its line, preferring a
+    /// whole-word occurrence, or `None` when the line lacks the name.
+    fn name_column(line: &str, name: &str) -> Option<usize> {
+        TagLocator::find_word(line, name).or_else(|| line.find(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::ctags::CtagsHandler;
+
+    fn entry(line: &str) -> CtagsEntry {
+        CtagsHandler::parse_tag(line, Path::new("/src")).unwrap()
+    }
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    fn located(tag: &str, text: &str) -> Option<(usize, usize, LocateStrategy, bool)> {
+        TagLocator::locate(&entry(tag), &lines(text))
+            .map(|found| (found.line, found.column, found.strategy, found.stale))
+    }
+
+    const SOURCE: &str = "#include <stdio.h>\n\nint compute(int a, int b);\n";
+
+    #[test]
+    fn line_field() {
+        assert_eq!(
+            located(
+                "compute\tf.c\t/^int compute(int a, int b);$/;\"\tp\tline:3",
+                SOURCE
+            ),
+            Some((2, 4, LocateStrategy::Line, false))
+        );
+        assert_eq!(
+            located("compute\tf.c\t3;\"\tp", SOURCE),
+            Some((2, 4, LocateStrategy::Line, false))
+        );
+    }
+
+    #[test]
+    fn line_without_the_name() {
+        // A line number pointing elsewhere has nothing to fall back on
+        assert_eq!(located("compute\tf.c\t1;\"\tp", SOURCE), None);
+        // A truncated pattern matching a line without the name is passed over
+        let text = "static int other(void);\nstatic int compute(int a);\n";
+        assert_eq!(
+            located("compute\tf.c\t/^static int/;\"\tp\tline:1", text),
+            Some((1, 11, LocateStrategy::Nearby, true))
+        );
+    }
+
+    #[test]
+    fn nearby_and_pattern() {
+        let moved = format!("\n\n{}", SOURCE);
+        assert_eq!(
+            located(
+                "compute\tf.c\t/^int compute(int a, int b);$/;\"\tp\tline:3",
+                &moved
+            ),
+            Some((4, 4, LocateStrategy::Nearby, true))
+        );
+        let far = format!("{}{}", "\n".repeat(NEARBY_WINDOW + 1), SOURCE);
+        assert_eq!(
+            located(
+                "compute\tf.c\t/^int compute(int a, int b);$/;\"\tp\tline:3",
+                &far
+            ),
+            Some((NEARBY_WINDOW + 3, 4, LocateStrategy::Pattern, true))
+        );
+        // Without a line field the pattern is all the tags file says
+        assert_eq!(
+            located("compute\tf.c\t/^int compute(int a, int b);$/;\"\tp", &moved),
+            Some((4, 4, LocateStrategy::Pattern, false))
+        );
+    }
+
+    #[test]
+    fn normalized_and_similar() {
+        let reformatted = "int  compute(int a,\tint b);\n";
+        assert_eq!(
+            located(
+                "compute\tf.c\t/^int compute(int a, int b);$/;\"\tp\tline:1",
+                reformatted
+            ),
+            Some((0, 5, LocateStrategy::Normalized, true))
+        );
+        let changed = "// compute(a, b)\nint compute(int a, int b, int c);\n";
+        assert_eq!(
+            located(
+                "compute\tf.c\t/^int compute(int a, int b);$/;\"\tp\tline:1",
+                changed
+            ),
+            Some((1, 4, LocateStrategy::Similar, true))
+        );
+        assert_eq!(
+            located(
+                "compute\tf.c\t/^int compute(int a, int b);$/;\"\tp\tline:1",
+                "int other(void);\n"
+            ),
+            None
+        );
+    }
+}
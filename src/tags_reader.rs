@@ -0,0 +1,399 @@
+use std::{cmp::Ordering, fs::File, io, path::Path};
+
+use memmap2::Mmap;
+
+const PSEUDO_TAG_PREFIX: &str = "!_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Unsorted,
+    Sorted,
+    FoldCase,
+}
+
+#[derive(Debug, Clone)]
+pub struct PseudoTag {
+    pub name: String,
+    pub value: String,
+}
+
+/// Reader for tags files in the extended ctags format.
+///
+/// The file is memory-mapped, so only the pages a lookup touches are read.
+/// Lookups use a binary search over the file when `!_TAG_FILE_SORTED` says the
+/// file is sorted, and fall back to a linear scan otherwise.
+pub struct TagsReader {
+    data: Mmap,
+    data_start: usize,
+    sort_order: SortOrder,
+    pseudo_tags: Vec<PseudoTag>,
+}
+
+impl TagsReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: ctags and this server replace tags files instead of writing
+        // them in place. A file changed in place anyway is mapped again by
+        // `TagIndex::load`, which compares its modification time and length.
+        let data = unsafe { Mmap::map(&file)? };
+        Ok(TagsReader::from_mmap(data))
+    }
+
+    fn from_mmap(data: Mmap) -> Self {
+        let mut pseudo_tags = Vec::new();
+        let mut data_start = 0;
+        while data[data_start..].starts_with(PSEUDO_TAG_PREFIX.as_bytes()) {
+            let line = TagsReader::line_at_offset(&data, data_start);
+            if let Some(tag) = Self::parse_pseudo_tag(&String::from_utf8_lossy(line)) {
+                pseudo_tags.push(tag);
+            }
+            data_start = TagsReader::next_line(&data, data_start);
+        }
+
+        let mut tags_reader = Self {
+            data,
+            data_start,
+            sort_order: SortOrder::Unsorted,
+            pseudo_tags,
+        };
+        tags_reader.sort_order = match tags_reader.pseudo_tag("TAG_FILE_SORTED") {
+            Some("1") => SortOrder::Sorted,
+            Some("2") => SortOrder::FoldCase,
+            _ => SortOrder::Unsorted,
+        };
+        tags_reader
+    }
+
+    /// Returns the value of the `!_<name>` pseudo-tag, if present.
+    pub fn pseudo_tag(&self, name: &str) -> Option<&str> {
+        self.pseudo_tags
+            .iter()
+            .find(|tag| tag.name == name)
+            .map(|tag| tag.value.as_str())
+    }
+
+    pub fn sort_order(&self) -> SortOrder {
+        self.sort_order
+    }
+
+    /// Returns the raw tag lines whose name is exactly `name`.
+    pub fn find(&self, name: &str) -> Vec<String> {
+        self.find_matching(name, false, false, usize::MAX)
+    }
+
+    /// Returns up to `limit` raw tag lines whose name starts with `prefix`,
+    /// like `readtags -p`.
+    pub fn find_prefix(&self, prefix: &str, limit: usize) -> Vec<String> {
+        self.find_matching(prefix, true, false, limit)
+    }
+
+    /// Like [`TagsReader::find_prefix`], ignoring ASCII case. Only files sorted
+    /// with case folding can be searched without a scan.
+    pub fn find_prefix_ignore_case(&self, prefix: &str, limit: usize) -> Vec<String> {
+        self.find_matching(prefix, true, true, limit)
+    }
+
+    fn find_matching(
+        &self,
+        key: &str,
+        prefix: bool,
+        ignore_case: bool,
+        limit: usize,
+    ) -> Vec<String> {
+        match (self.sort_order, ignore_case) {
+            (SortOrder::Sorted, false) | (SortOrder::FoldCase, _) => {
+                self.find_binary(key.as_bytes(), prefix, ignore_case, limit)
+            }
+            _ => self.find_linear(key.as_bytes(), prefix, ignore_case, limit),
+        }
+    }
+
+    /// Returns the offset and content of every tag line in file order.
+    pub fn lines(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.lines_from(self.data_start)
+    }
+
+    /// Like [`TagsReader::lines`], starting at the line at `offset`.
+    fn lines_from(&self, mut offset: usize) -> impl Iterator<Item = (usize, &[u8])> {
+        std::iter::from_fn(move || {
+            if offset >= self.data.len() {
+                return None;
+            }
+            let start = offset;
+            offset = TagsReader::next_line(&self.data, offset);
+            Some((start, TagsReader::line_at_offset(&self.data, start)))
+        })
+    }
+
+    /// Returns the tag line starting at `offset`, as yielded by
+    /// [`TagsReader::lines`].
+    pub fn line_at(&self, offset: usize) -> &[u8] {
+        TagsReader::line_at_offset(&self.data, offset)
+    }
+
+    /// Calls `visit` with every tag line in file order, stopping at the first
+    /// error it returns.
+    pub fn try_for_each<E>(&self, mut visit: impl FnMut(&str) -> Result<(), E>) -> Result<(), E> {
+        for (_, line) in self.lines() {
+            visit(&String::from_utf8_lossy(line))?;
+        }
+        Ok(())
+    }
+
+    fn parse_pseudo_tag(line: &str) -> Option<PseudoTag> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let mut parts = line.splitn(3, '\t');
+        let name = parts.next()?.strip_prefix(PSEUDO_TAG_PREFIX)?;
+        let value = parts.next().unwrap_or_default();
+        Some(PseudoTag {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    /// Returns the line starting at `offset` without its line terminator.
+    fn line_at_offset(data: &[u8], offset: usize) -> &[u8] {
+        let rest = &data[offset..];
+        let end = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+        let line = &rest[..end];
+        line.strip_suffix(b"\r").unwrap_or(line)
+    }
+
+    /// Returns the offset of the line after the one containing `offset`.
+    fn next_line(data: &[u8], offset: usize) -> usize {
+        data[offset..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(data.len(), |end| offset + end + 1)
+    }
+
+    pub fn tag_name(line: &[u8]) -> &[u8] {
+        let end = line.iter().position(|&b| b == b'\t').unwrap_or(line.len());
+        &line[..end]
+    }
+
+    /// Compares names the way ctags sorts them, folding to upper case for
+    /// case-insensitive files.
+    pub fn compare_names(tag_name: &[u8], name: &[u8], fold_case: bool) -> Ordering {
+        if fold_case {
+            tag_name
+                .iter()
+                .map(u8::to_ascii_uppercase)
+                .cmp(name.iter().map(u8::to_ascii_uppercase))
+        } else {
+            tag_name.cmp(name)
+        }
+    }
+
+    pub fn is_match(tag_name: &[u8], key: &[u8], prefix: bool, ignore_case: bool) -> bool {
+        let tag_name = if prefix {
+            &tag_name[..tag_name.len().min(key.len())]
+        } else {
+            tag_name
+        };
+        if ignore_case {
+            tag_name.eq_ignore_ascii_case(key)
+        } else {
+            tag_name == key
+        }
+    }
+
+    fn find_binary(
+        &self,
+        key: &[u8],
+        prefix: bool,
+        ignore_case: bool,
+        limit: usize,
+    ) -> Vec<String> {
+        let fold_case = self.sort_order == SortOrder::FoldCase;
+
+        // Every line starting before `low` sorts before `key`, and every line
+        // starting at or after `high` sorts at or after it.
+        let mut low = self.data_start;
+        let mut high = self.data.len();
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let start = if mid == 0 {
+                0
+            } else {
+                TagsReader::next_line(&self.data, mid - 1)
+            };
+            if start >= high {
+                high = mid;
+                continue;
+            }
+            let line = self.line_at(start);
+            if TagsReader::compare_names(TagsReader::tag_name(line), key, fold_case)
+                == Ordering::Less
+            {
+                low = TagsReader::next_line(&self.data, start);
+            } else {
+                high = start;
+            }
+        }
+
+        let mut lines = Vec::new();
+        for (_, line) in self.lines_from(low) {
+            if lines.len() >= limit {
+                break;
+            }
+            let tag_name = TagsReader::tag_name(line);
+            let compared = if prefix {
+                &tag_name[..tag_name.len().min(key.len())]
+            } else {
+                tag_name
+            };
+            if TagsReader::compare_names(compared, key, fold_case) != Ordering::Equal {
+                break;
+            }
+            if TagsReader::is_match(tag_name, key, prefix, ignore_case) {
+                lines.push(TagsReader::decode_line(line));
+            }
+        }
+        lines
+    }
+
+    fn find_linear(
+        &self,
+        key: &[u8],
+        prefix: bool,
+        ignore_case: bool,
+        limit: usize,
+    ) -> Vec<String> {
+        self.lines()
+            .map(|(_, line)| line)
+            .filter(|line| {
+                TagsReader::is_match(TagsReader::tag_name(line), key, prefix, ignore_case)
+            })
+            .take(limit)
+            .map(TagsReader::decode_line)
+            .collect()
+    }
+
+    pub fn decode_line(line: &[u8]) -> String {
+        String::from_utf8_lossy(line).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a tags file with the `!_TAG_FILE_SORTED` value `sorted` and a
+    /// line per tag name, in the given order.
+    fn reader(sorted: &str, names: &[&str], newline: &str) -> TagsReader {
+        let mut data = format!("!_TAG_FILE_FORMAT\t2\t/extended format/{newline}");
+        data.push_str(&format!(
+            "!_TAG_FILE_SORTED\t{sorted}\t/0=unsorted/{newline}"
+        ));
+        for (idx, name) in names.iter().enumerate() {
+            data.push_str(&format!(
+                "{name}\tfile.c\t/^{name}$/;\"\tf\tline:{idx}{newline}"
+            ));
+        }
+        let mut mapping = memmap2::MmapMut::map_anon(data.len()).unwrap();
+        mapping.copy_from_slice(data.as_bytes());
+        TagsReader::from_mmap(mapping.make_read_only().unwrap())
+    }
+
+    fn names(lines: &[String]) -> Vec<&str> {
+        lines
+            .iter()
+            .map(|line| line.split('\t').next().unwrap())
+            .collect()
+    }
+
+    const SORTED: &[&str] = &["Bar", "abc", "foo", "foo", "foo_bar", "foobar", "zed"];
+    const FOLD_CASE: &[&str] = &["abc", "Bar", "foo", "Foo", "FOOD", "foo_bar", "zed"];
+
+    #[test]
+    fn reads_sort_order() {
+        assert_eq!(reader("1", SORTED, "\n").sort_order(), SortOrder::Sorted);
+        assert_eq!(
+            reader("2", FOLD_CASE, "\n").sort_order(),
+            SortOrder::FoldCase
+        );
+        assert_eq!(reader("0", SORTED, "\n").sort_order(), SortOrder::Unsorted);
+    }
+
+    #[test]
+    fn sorted_find_skips_longer_names() {
+        let reader = reader("1", SORTED, "\n");
+        assert_eq!(names(&reader.find("foo")), ["foo", "foo"]);
+        assert_eq!(names(&reader.find("foo_bar")), ["foo_bar"]);
+        assert_eq!(
+            names(&reader.find_prefix("foo", usize::MAX)),
+            ["foo", "foo", "foo_bar", "foobar"]
+        );
+        assert_eq!(
+            names(&reader.find_prefix("foo", 3)),
+            ["foo", "foo", "foo_bar"]
+        );
+    }
+
+    #[test]
+    fn sorted_find_first_and_last_lines() {
+        let reader = reader("1", SORTED, "\n");
+        assert_eq!(names(&reader.find("Bar")), ["Bar"]);
+        assert_eq!(names(&reader.find("zed")), ["zed"]);
+        assert!(reader.find("AAA").is_empty());
+        assert!(reader.find("fo").is_empty());
+        assert!(reader.find("zzz").is_empty());
+    }
+
+    #[test]
+    fn sorted_find_with_crlf() {
+        let reader = reader("1", SORTED, "\r\n");
+        let found = reader.find("foo");
+        assert_eq!(names(&found), ["foo", "foo"]);
+        assert!(found.iter().all(|line| !line.ends_with('\r')));
+        assert_eq!(reader.find("zed"), ["zed\tfile.c\t/^zed$/;\"\tf\tline:6"]);
+        assert_eq!(names(&reader.find("Bar")), ["Bar"]);
+    }
+
+    #[test]
+    fn fold_case_find() {
+        let reader = reader("2", FOLD_CASE, "\n");
+        assert_eq!(names(&reader.find("foo")), ["foo"]);
+        assert_eq!(names(&reader.find("Foo")), ["Foo"]);
+        assert_eq!(names(&reader.find("abc")), ["abc"]);
+        assert_eq!(names(&reader.find("zed")), ["zed"]);
+        assert_eq!(
+            names(&reader.find_prefix_ignore_case("foo", usize::MAX)),
+            ["foo", "Foo", "FOOD", "foo_bar"]
+        );
+        assert_eq!(
+            names(&reader.find_prefix_ignore_case("BAR", usize::MAX)),
+            ["Bar"]
+        );
+    }
+
+    #[test]
+    fn unsorted_find_scans_every_line() {
+        let reader = reader("0", &["zed", "foo_bar", "foo", "abc", "foo"], "\r\n");
+        assert_eq!(names(&reader.find("foo")), ["foo", "foo"]);
+        assert_eq!(names(&reader.find("zed")), ["zed"]);
+        assert_eq!(
+            names(&reader.find_prefix_ignore_case("FOO", usize::MAX)),
+            ["foo_bar", "foo", "foo"]
+        );
+    }
+
+    #[test]
+    fn sorted_find_every_line_of_large_file() {
+        let tags: Vec<String> = (0..500).map(|idx| format!("tag{idx:04}")).collect();
+        let tags: Vec<&str> = tags.iter().map(String::as_str).collect();
+        let reader = reader("1", &tags, "\n");
+        for tag in &tags {
+            assert_eq!(names(&reader.find(tag)), [*tag]);
+        }
+        assert!(reader.find("tag0500").is_empty());
+    }
+
+    #[test]
+    fn empty_file() {
+        let reader = reader("1", &[], "\n");
+        assert!(reader.find("foo").is_empty());
+        assert_eq!(reader.lines().count(), 0);
+    }
+}
@@ -1,34 +1,198 @@
-use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::logger::Logger;
-use lsp_types::WorkspaceFolder;
+use crate::{
+    logger::Logger, position_encoding::PositionEncoding, tag_generator::TagGenerator,
+    tag_index::TagIndex,
+};
+use lsp_types::{Url, WorkspaceFolder};
+use serde::Deserialize;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct TagFile {
+    pub path: String,
+    /// Files with a lower value are queried first.
+    pub priority: i32,
+}
+
+#[derive(Debug, Clone)]
 pub struct Workspace {
     pub folder: WorkspaceFolder,
-    pub tag_file_path: Option<String>,
+    pub tag_files: Vec<TagFile>,
+    /// Raw tag lines of files re-tagged since the tags files were written, by
+    /// file path. They replace whatever the tags files say about those files.
+    pub overlay: HashMap<String, Vec<String>>,
+}
+
+impl Workspace {
+    pub fn contains(&self, path: &Path) -> bool {
+        self.folder
+            .uri
+            .to_file_path()
+            .is_ok_and(|folder| path.starts_with(folder))
+    }
+}
+
+/// A tags file pattern from the `tags` initialization option. It is either a
+/// plain string or an object with an explicit priority.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum TagFilePattern {
+    Pattern(String),
+    WithPriority { pattern: String, priority: i32 },
+}
+
+impl TagFilePattern {
+    pub fn pattern(&self) -> &str {
+        match self {
+            TagFilePattern::Pattern(pattern) => pattern,
+            TagFilePattern::WithPriority { pattern, .. } => pattern,
+        }
+    }
+
+    /// Patterns without an explicit priority rank by their position in the list.
+    pub fn priority(&self, index: usize) -> i32 {
+        match self {
+            TagFilePattern::Pattern(_) => index as i32,
+            TagFilePattern::WithPriority { priority, .. } => *priority,
+        }
+    }
+}
+
+impl From<&str> for TagFilePattern {
+    fn from(pattern: &str) -> Self {
+        TagFilePattern::Pattern(pattern.to_string())
+    }
+}
+
+/// Default maximum number of completion items returned per request.
+const DEFAULT_COMPLETION_LIMIT: usize = 100;
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How `ctags` is run for workspaces, from the `ctags` initialization option.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CtagsConfig {
+    pub command: String,
+    pub args: Vec<String>,
+    /// Passed to ctags as `--exclude` patterns.
+    pub exclude: Vec<String>,
+    /// Whether to generate tags for workspaces without any tags file.
+    pub auto_generate: bool,
+    /// Whether to rewrite the tags files with the re-tagged files on save.
+    pub rewrite_on_save: bool,
+    /// Where generated tags files go, the user cache directory by default.
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for CtagsConfig {
+    fn default() -> Self {
+        Self {
+            command: "ctags".to_string(),
+            args: vec!["--recurse".to_string(), "--fields=+neKSlt".to_string()],
+            exclude: vec![
+                ".git".to_string(),
+                "node_modules".to_string(),
+                "target".to_string(),
+            ],
+            auto_generate: true,
+            rewrite_on_save: false,
+            cache_dir: None,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct WorkspaceConfig {
-    pub tag_file_patterns: Vec<String>,
+    pub tag_file_patterns: Vec<TagFilePattern>,
+    pub completion_limit: usize,
+    /// Extra identifier characters per language id, e.g. `-` for `lisp`.
+    pub word_characters: HashMap<String, String>,
+    pub ctags: CtagsConfig,
+    /// How long a request may run before it is abandoned, unlimited when
+    /// `None`.
+    pub request_timeout: Option<Duration>,
+    /// How position columns are counted, as negotiated with the client.
+    pub position_encoding: PositionEncoding,
+}
+
+impl Default for WorkspaceConfig {
+    fn default() -> Self {
+        Self {
+            tag_file_patterns: vec!["tags".into()],
+            completion_limit: DEFAULT_COMPLETION_LIMIT,
+            word_characters: HashMap::new(),
+            ctags: CtagsConfig::default(),
+            request_timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            position_encoding: PositionEncoding::default(),
+        }
+    }
+}
+
+/// The workspaces and configuration as they were when a request started.
+/// Requests look tags up in a snapshot so that they do not hold the lock of
+/// the workspace manager, which changes to the workspaces would wait for.
+#[derive(Clone)]
+pub struct WorkspaceSnapshot {
+    pub workspaces: Arc<Vec<Workspace>>,
+    pub config: Arc<WorkspaceConfig>,
 }
 
 pub struct WorkspaceManager {
-    pub workspaces: Vec<Workspace>,
-    pub config: WorkspaceConfig,
+    /// Shared with the snapshots taken from it. Changes copy the workspaces
+    /// first while a snapshot still holds them.
+    pub workspaces: Arc<Vec<Workspace>>,
+    pub config: Arc<WorkspaceConfig>,
+    /// Workspace folders ctags is currently running for.
+    pub generating: HashSet<Url>,
 }
 
 impl WorkspaceManager {
-    pub fn new(tag_patterns: Vec<String>) -> Self {
+    pub fn new(config: WorkspaceConfig) -> Self {
         Self {
-            workspaces: Vec::new(),
-            config: WorkspaceConfig {
-                tag_file_patterns: tag_patterns,
-            },
+            workspaces: Arc::new(Vec::new()),
+            config: Arc::new(config),
+            generating: HashSet::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> WorkspaceSnapshot {
+        WorkspaceSnapshot {
+            workspaces: self.workspaces.clone(),
+            config: self.config.clone(),
         }
     }
 
+    pub fn workspaces_mut(&mut self) -> &mut Vec<Workspace> {
+        Arc::make_mut(&mut self.workspaces)
+    }
+
+    /// Returns the index in `workspaces` of the innermost workspace containing
+    /// `path`, which owns it when workspace folders are nested.
+    pub fn innermost(workspaces: &[Workspace], path: &Path) -> Option<usize> {
+        workspaces
+            .iter()
+            .enumerate()
+            .filter(|(_, w)| w.contains(path))
+            .max_by_key(|(_, w)| w.folder.uri.as_str().len())
+            .map(|(idx, _)| idx)
+    }
+
+    /// Returns the innermost workspace containing `path`.
+    pub fn workspace_for(&self, path: &Path) -> Option<&Workspace> {
+        WorkspaceManager::innermost(&self.workspaces, path).map(|idx| &self.workspaces[idx])
+    }
+
+    /// Like [`WorkspaceManager::workspace_for`], for changing the workspace.
+    pub fn workspace_for_mut(&mut self, path: &Path) -> Option<&mut Workspace> {
+        let idx = WorkspaceManager::innermost(&self.workspaces, path)?;
+        Some(&mut self.workspaces_mut()[idx])
+    }
+
     pub fn add_workspace(&mut self, folder: &WorkspaceFolder) {
         if self.workspaces.iter().any(|w| w.folder.uri == folder.uri) {
             Logger::info(&format!("Workspace already exists: {}", folder.uri));
@@ -41,27 +205,235 @@ impl WorkspaceManager {
             return;
         };
 
-        let mut tag_file_path = None;
-        for pattern in &self.config.tag_file_patterns {
-            let tags_path = format!("{}/{}", folder_path.display(), pattern);
-            if Path::new(&tags_path).exists() {
-                tag_file_path = Some(tags_path);
-                break;
-            }
-        }
+        let tag_files = self.resolve_tag_files(&folder_path);
 
         Logger::info(&format!(
-            "Adding workspace: {:?} with tag file: {:?}",
-            folder, tag_file_path
+            "Adding workspace: {:?} with tag files: {:?}",
+            folder, tag_files
         ));
 
-        self.workspaces.push(Workspace {
+        self.workspaces_mut().push(Workspace {
             folder: folder.clone(),
-            tag_file_path,
+            tag_files,
+            overlay: HashMap::new(),
         });
     }
 
+    /// Registers a tags file generated for the workspace at `uri`. Generated
+    /// files rank after the configured ones.
+    pub fn add_generated_tag_file(&mut self, uri: &Url, path: &Path) {
+        let path = path.display().to_string();
+        if let Some(workspace) = self
+            .workspaces_mut()
+            .iter_mut()
+            .find(|w| w.folder.uri == *uri)
+            && !workspace
+                .tag_files
+                .iter()
+                .any(|tag_file| tag_file.path == path)
+        {
+            workspace.tag_files.push(TagFile {
+                path,
+                priority: i32::MAX,
+            });
+        }
+    }
+
     pub fn remove_workspace(&mut self, folder: &WorkspaceFolder) {
-        self.workspaces.retain(|w| w.folder != *folder);
+        self.workspaces_mut().retain(|w| w.folder != *folder);
+    }
+
+    /// Resolves the tags files of every workspace again after the files at
+    /// `changed` were created, regenerated or deleted. The overlay of a
+    /// workspace whose tags files changed is dropped, since those tags files
+    /// are now newer than it.
+    pub fn refresh_tag_files(&mut self, changed: &[PathBuf]) {
+        for path in changed {
+            TagIndex::invalidate(path);
+        }
+        for idx in 0..self.workspaces.len() {
+            let Ok(folder_path) = self.workspaces[idx].folder.uri.to_file_path() else {
+                continue;
+            };
+            let tag_files = self.resolve_tag_files(&folder_path);
+            let workspace = &mut self.workspaces_mut()[idx];
+            let touched = |tag_files: &[TagFile]| {
+                tag_files
+                    .iter()
+                    .any(|tag_file| changed.iter().any(|path| Path::new(&tag_file.path) == path))
+            };
+            if touched(&workspace.tag_files) || touched(&tag_files) {
+                workspace.overlay.clear();
+            }
+            Logger::info(&format!(
+                "Refreshed tag files of {}: {:?}",
+                workspace.folder.uri, tag_files
+            ));
+            workspace.tag_files = tag_files;
+        }
+    }
+
+    /// Compiles the tags file and exclude patterns for matching many paths
+    /// against the workspaces as they are now, without holding the lock.
+    pub fn path_matcher(&self) -> PathMatcher {
+        let folders: Vec<Option<PathBuf>> = self
+            .workspaces
+            .iter()
+            .map(|workspace| workspace.folder.uri.to_file_path().ok())
+            .collect();
+        let tag_file_patterns = folders
+            .iter()
+            .map(|folder_path| {
+                let Some(folder_path) = folder_path else {
+                    return Vec::new();
+                };
+                self.config
+                    .tag_file_patterns
+                    .iter()
+                    .filter_map(|pattern| {
+                        glob::Pattern::new(&WorkspaceManager::full_pattern(pattern, folder_path))
+                            .ok()
+                    })
+                    .collect()
+            })
+            .collect();
+        let generated = folders
+            .iter()
+            .map(|folder_path| {
+                folder_path.as_deref().and_then(|folder_path| {
+                    TagGenerator::output_path(&self.config.ctags, folder_path)
+                })
+            })
+            .collect();
+        let excludes = self
+            .config
+            .ctags
+            .exclude
+            .iter()
+            .filter_map(|exclude| glob::Pattern::new(exclude).ok())
+            .collect();
+        PathMatcher {
+            workspaces: self.workspaces.clone(),
+            folders,
+            tag_file_patterns,
+            generated,
+            excludes,
+        }
+    }
+
+    /// Finds the tags files of the workspace at `folder_path`, falling back to
+    /// the tags generated for it by an earlier session.
+    fn resolve_tag_files(&self, folder_path: &Path) -> Vec<TagFile> {
+        let mut tag_files = self.find_tag_files(folder_path);
+        if tag_files.is_empty()
+            && let Some(generated) = TagGenerator::output_path(&self.config.ctags, folder_path)
+            && generated.is_file()
+        {
+            tag_files.push(TagFile {
+                path: generated.display().to_string(),
+                priority: i32::MAX,
+            });
+        }
+        tag_files
+    }
+
+    /// Relative patterns are resolved against the workspace folder.
+    fn full_pattern(pattern: &TagFilePattern, folder_path: &Path) -> String {
+        if Path::new(pattern.pattern()).is_absolute() {
+            pattern.pattern().to_string()
+        } else {
+            format!(
+                "{}/{}",
+                glob::Pattern::escape(&folder_path.display().to_string()),
+                pattern.pattern()
+            )
+        }
+    }
+
+    /// Resolves every tags file pattern, including glob patterns, against the
+    /// workspace folder. The result is ordered by priority.
+    fn find_tag_files(&self, folder_path: &Path) -> Vec<TagFile> {
+        let mut tag_files: Vec<TagFile> = Vec::new();
+        for (index, pattern) in self.config.tag_file_patterns.iter().enumerate() {
+            let full_pattern = WorkspaceManager::full_pattern(pattern, folder_path);
+
+            let paths = match glob::glob(&full_pattern) {
+                Ok(paths) => paths,
+                Err(e) => {
+                    Logger::error(&format!(
+                        "Invalid tags file pattern {}: {:?}",
+                        pattern.pattern(),
+                        e
+                    ));
+                    continue;
+                }
+            };
+
+            for path in paths.flatten().filter(|path| path.is_file()) {
+                let path = path.display().to_string();
+                if tag_files.iter().any(|tag_file| tag_file.path == path) {
+                    continue;
+                }
+                tag_files.push(TagFile {
+                    path,
+                    priority: pattern.priority(index),
+                });
+            }
+        }
+
+        tag_files.sort_by_key(|tag_file| tag_file.priority);
+        tag_files
+    }
+}
+
+/// The tags file and exclude patterns of the workspaces, compiled once by
+/// [`WorkspaceManager::path_matcher`].
+pub struct PathMatcher {
+    workspaces: Arc<Vec<Workspace>>,
+    /// The folder of each workspace, with its tags file patterns and the
+    /// generated tags file at the same index.
+    folders: Vec<Option<PathBuf>>,
+    tag_file_patterns: Vec<Vec<glob::Pattern>>,
+    generated: Vec<Option<PathBuf>>,
+    excludes: Vec<glob::Pattern>,
+}
+
+impl PathMatcher {
+    /// Whether `path` is, or could become, a tags file of some workspace.
+    pub fn is_tag_file(&self, path: &Path) -> bool {
+        self.workspaces.iter().enumerate().any(|(idx, workspace)| {
+            workspace
+                .tag_files
+                .iter()
+                .any(|tag_file| Path::new(&tag_file.path) == path)
+                || self.generated[idx].as_deref() == Some(path)
+                || self.tag_file_patterns[idx]
+                    .iter()
+                    .any(|pattern| pattern.matches_path(path))
+        })
+    }
+
+    /// Whether `path` lies in some workspace.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.folders
+            .iter()
+            .flatten()
+            .any(|folder_path| path.starts_with(folder_path))
+    }
+
+    /// Whether `path` lies in a directory or is a file excluded from tagging.
+    /// Only the part of the path inside its workspace folder is matched, so
+    /// that directories above the folder exclude nothing.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let relative = WorkspaceManager::innermost(&self.workspaces, path)
+            .and_then(|idx| self.folders[idx].as_deref())
+            .and_then(|folder_path| path.strip_prefix(folder_path).ok())
+            .unwrap_or(path);
+        relative.components().any(|component| {
+            let component = component.as_os_str().to_string_lossy();
+            self.excludes
+                .iter()
+                .any(|exclude| exclude.matches(&component))
+        })
     }
 }
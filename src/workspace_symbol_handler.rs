@@ -0,0 +1,184 @@
+use std::{collections::HashSet, io};
+
+use lsp_server::{Message, Request, Response};
+use lsp_types::{Location, Position, Range, SymbolInformation, Url, WorkspaceSymbolParams};
+
+use crate::{
+    cancellation::CancellationToken,
+    ctags::{CtagsEntry, CtagsHandler},
+    goto_handler::locate_tags,
+    logger::Logger,
+    lsp_error::LspError,
+    LspServer,
+};
+
+/// Maximum number of symbols returned for a single query.
+const MAX_WORKSPACE_SYMBOLS: usize = 256;
+
+struct Candidate {
+    score: u32,
+    order: usize,
+    entry: CtagsEntry,
+}
+
+pub struct WorkspaceSymbolHandler;
+
+impl WorkspaceSymbolHandler {
+    /// Scores how well `name` matches `query`, or `None` when it does not match.
+    /// Exact matches rank above prefixes, prefixes above substrings and
+    /// substrings above fuzzy (subsequence) matches.
+    fn match_score(name: &str, query: &str) -> Option<u32> {
+        if query.is_empty() {
+            return Some(0);
+        }
+        if name == query {
+            return Some(4000);
+        }
+
+        let name_lower = name.to_lowercase();
+        let query_lower = query.to_lowercase();
+        let length_penalty = name.len().min(999) as u32;
+        if name_lower == query_lower {
+            return Some(3900);
+        }
+        if name.starts_with(query) {
+            return Some(3000 - length_penalty);
+        }
+        if name_lower.starts_with(&query_lower) {
+            return Some(2900 - length_penalty);
+        }
+        if let Some(pos) = name_lower.find(&query_lower) {
+            return Some(2000 - pos.min(999) as u32);
+        }
+
+        // Fuzzy match: every query character appears in order. Gaps between
+        // matched characters lower the score.
+        let mut gaps = 0;
+        let mut name_chars = name_lower.chars();
+        for query_char in query_lower.chars() {
+            loop {
+                match name_chars.next() {
+                    Some(c) if c == query_char => break,
+                    Some(_) => gaps += 1,
+                    None => return None,
+                }
+            }
+        }
+        Some(1000u32.saturating_sub(gaps))
+    }
+
+    fn location(entry: &CtagsEntry) -> Option<Location> {
+        let line = entry.line?.checked_sub(1)?;
+        let uri = Url::from_file_path(&entry.file).ok()?;
+        let position = Position { line, character: 0 };
+        Some(Location {
+            uri,
+            range: Range {
+                start: position,
+                end: position,
+            },
+        })
+    }
+
+    fn search(
+        &self,
+        server: &LspServer,
+        query: &str,
+        cancel: &CancellationToken,
+    ) -> io::Result<Vec<SymbolInformation>> {
+        let snapshot = server.workspace_manager.read().unwrap().snapshot();
+
+        let mut candidates: Vec<Candidate> = Vec::new();
+        let mut order = 0;
+        CtagsHandler::scan_ctags(&snapshot.workspaces, cancel, |base_dir, name, line| {
+            let Some(score) = WorkspaceSymbolHandler::match_score(name, query) else {
+                return;
+            };
+            let Some(entry) = CtagsHandler::parse_tag(line, base_dir) else {
+                return;
+            };
+            candidates.push(Candidate {
+                score,
+                order,
+                entry,
+            });
+            order += 1;
+
+            // Keep memory bounded on huge tags files
+            if candidates.len() >= MAX_WORKSPACE_SYMBOLS * 4 {
+                WorkspaceSymbolHandler::rank(&mut candidates);
+            }
+        })?;
+        WorkspaceSymbolHandler::rank(&mut candidates);
+
+        // Tags without a `line:` field need their pattern looked up in the file.
+        let unresolved: Vec<CtagsEntry> = candidates
+            .iter()
+            .filter(|candidate| candidate.entry.line.is_none())
+            .map(|candidate| candidate.entry.clone())
+            .collect();
+        let mut resolved = locate_tags(
+            &unresolved,
+            &server.documents,
+            snapshot.config.position_encoding,
+            cancel,
+        )?
+        .into_iter();
+
+        let mut symbols = Vec::new();
+        for candidate in candidates {
+            let location = match candidate.entry.line {
+                Some(_) => WorkspaceSymbolHandler::location(&candidate.entry),
+                None => resolved.next().flatten().map(|tag| tag.location),
+            };
+            let Some(location) = location else {
+                continue;
+            };
+            #[allow(deprecated)]
+            symbols.push(SymbolInformation {
+                name: candidate.entry.name.clone(),
+                kind: candidate.entry.symbol_kind(),
+                tags: None,
+                deprecated: None,
+                location,
+                container_name: candidate.entry.scope.map(|scope| scope.name),
+            });
+        }
+        Ok(symbols)
+    }
+
+    /// Sorts candidates best first, removes duplicates and drops everything
+    /// past the limit.
+    fn rank(candidates: &mut Vec<Candidate>) {
+        candidates.sort_by(|a, b| {
+            b.score
+                .cmp(&a.score)
+                .then_with(|| a.entry.name.len().cmp(&b.entry.name.len()))
+                .then_with(|| a.order.cmp(&b.order))
+        });
+        let mut seen = HashSet::new();
+        candidates.retain(|candidate| seen.insert(candidate.entry.clone()));
+        candidates.truncate(MAX_WORKSPACE_SYMBOLS);
+    }
+
+    pub fn handle(&self, req: Request, server: &LspServer) -> Result<(), LspError> {
+        Logger::info(&format!("Received request: {:?}", req.method));
+        let params: WorkspaceSymbolParams = serde_json::from_value(req.params)?;
+
+        let cancel = server.cancellation_token(&req.id);
+        let symbols = self.search(server, &params.query, &cancel)?;
+        Logger::info(&format!(
+            "Found {} workspace symbols for query: {}",
+            symbols.len(),
+            params.query
+        ));
+
+        let resp = Response::new_ok(req.id.clone(), symbols);
+        server
+            .connection
+            .sender
+            .send(Message::Response(resp))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(())
+    }
+}